@@ -1,5 +1,12 @@
+use async_trait::async_trait;
 use aws_config::{meta::region::RegionProviderChain, BehaviorVersion, Region};
 use aws_sdk_bedrockruntime::operation::converse::ConverseError;
+use std::error::Error;
+
+use crate::ai::{embedding, llm, vision};
+use crate::ai::provider::{Capability, Provider, ProviderConfig};
+use crate::processing::faces::DiscoveredFace;
+use crate::processing::metadata::{BoundingBox, FaceRegion, FaceSource};
 
 #[derive(Debug)]
 pub struct BedrockConverseError(String);
@@ -36,4 +43,88 @@ pub async fn bedrock_client() -> aws_sdk_bedrockruntime::Client {
         .load()
         .await;
     aws_sdk_bedrockruntime::Client::new(&bedrock_shared_config)
-}
\ No newline at end of file
+}
+
+// Bedrock for LLM and embeddings, rekognition for faces
+pub struct BedrockProvider {
+    chat_model: String,
+    vision_model: String,
+    embedding_model: String,
+    image_embedding_model: String,
+    max_image_size: u32,
+}
+
+impl BedrockProvider {
+    pub fn new(config: &ProviderConfig) -> Self {
+        BedrockProvider {
+            chat_model: config.chat_model(llm::DEFAULT_BEDROCK_MODEL_ID),
+            vision_model: config.vision_model(llm::DEFAULT_BEDROCK_MODEL_ID),
+            embedding_model: config.embedding_model(embedding::DEFAULT_BEDROCK_MODEL_ID),
+            image_embedding_model: config.image_embedding_model(embedding::DEFAULT_BEDROCK_IMAGE_MODEL_ID),
+            max_image_size: config.max_image_size,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for BedrockProvider {
+    fn name(&self) -> &str {
+        "bedrock"
+    }
+
+    fn capabilities(&self) -> &[Capability] {
+        &[Capability::DescribeImage, Capability::Complete, Capability::Embed, Capability::ImageEmbed, Capability::CompareFaces, Capability::FaceCollection, Capability::DiscoverFaces]
+    }
+
+    async fn describe_image(&self, image_path: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+        llm::describe_image_bedrock(&self.vision_model, image_path, prompt).await
+    }
+
+    async fn complete(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        llm::converse(&self.chat_model, prompt).await
+    }
+
+    fn embedding_model(&self) -> &str {
+        &self.embedding_model
+    }
+
+    async fn generate_embedding(&self, text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        embedding::generate_embedding_bedrock(&self.embedding_model, text.to_string()).await
+    }
+
+    fn image_embedding_model(&self) -> &str {
+        &self.image_embedding_model
+    }
+
+    async fn generate_image_embedding(&self, image_path: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        embedding::generate_embedding_bedrock_multimodal(&self.image_embedding_model, Some(image_path), None).await
+    }
+
+    async fn generate_image_text_embedding(&self, text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        embedding::generate_embedding_bedrock_multimodal(&self.image_embedding_model, None, Some(text)).await
+    }
+
+    fn face_source(&self) -> Option<FaceSource> {
+        Some(FaceSource::Rekognition)
+    }
+
+    async fn detect_faces(&self, image_path: &str) -> Result<Vec<BoundingBox>, Box<dyn Error>> {
+        vision::detect_faces(image_path, self.max_image_size).await
+    }
+
+    async fn compare_faces(&self, reference_file: &str, target_file: &str) -> Result<Vec<(BoundingBox, f32)>, Box<dyn Error>> {
+        vision::compare_faces(reference_file, target_file, self.max_image_size).await
+    }
+
+    async fn enroll_face(&self, collection: &str, person_name: &str, image_path: &str) -> Result<usize, Box<dyn Error>> {
+        vision::enroll_face(collection, person_name, image_path, self.max_image_size).await
+    }
+
+    async fn search_faces(&self, collection: &str, image_path: &str, threshold: f32) -> Result<Vec<FaceRegion>, Box<dyn Error>> {
+        vision::search_faces(collection, image_path, threshold, self.max_image_size).await
+    }
+
+    async fn discover_faces(&self, image_paths: &[String], threshold: f32) -> Result<Vec<Vec<DiscoveredFace>>, Box<dyn Error>> {
+        vision::discover_faces(image_paths, threshold, self.max_image_size).await
+    }
+}
@@ -1,34 +1,49 @@
 use std::error::Error;
 
 use crate::ai::bedrock::bedrock_client;
+use crate::ai::provider::Provider;
+use crate::graphics::images::{clear_temp_file, resize_temp_image};
+use crate::processing::metadata::EmbeddingSpace;
+use async_openai::{Client, config::OpenAIConfig};
 use async_openai::types::CreateEmbeddingRequestArgs;
 use aws_sdk_bedrockruntime::primitives::Blob;
+use base64::{engine::general_purpose, Engine};
 
-const BEDROCK_MODEL_ID: &str = "cohere.embed-english-v3";
-const OPENAI_MODEL_ID: &str = "text-embedding-3-large";
-
-pub async fn generate_embedding(provider: &str, text: String) -> (String, Result<Vec<f64>, Box<dyn Error>>) {
-    match provider {
-        "openai" => {
-            let embeddings = generate_embedding_openai(text).await;
-            return (OPENAI_MODEL_ID.to_string(), embeddings);
-        }
-        "bedrock" => {
-            let embeddings = generate_embedding_bedrock(text).await;
-            return (BEDROCK_MODEL_ID.to_string(), embeddings);
-        }
-        _ => {
-            return (
-                provider.to_string(),
-                Err(Box::from("Invalid provider")),
-            );
-        }
+// Defaults, override with --embedding-model
+pub const DEFAULT_BEDROCK_MODEL_ID: &str = "cohere.embed-english-v3";
+pub const DEFAULT_OPENAI_MODEL_ID: &str = "text-embedding-3-large";
+// Override with --image-embedding-model
+pub const DEFAULT_BEDROCK_IMAGE_MODEL_ID: &str = "amazon.titan-embed-image-v1";
+
+pub async fn generate_embedding(provider: &dyn Provider, text: String) -> (String, Result<Vec<f64>, Box<dyn Error>>) {
+    let embeddings = provider.generate_embedding(&text).await;
+    (provider.embedding_model().to_string(), embeddings)
+}
+
+pub async fn generate_image_embedding(provider: &dyn Provider, file_path: &str, max_size: u32) -> (String, Result<Vec<f64>, Box<dyn Error>>) {
+    let model = provider.image_embedding_model().to_string();
+    let tmp_file_path = match resize_temp_image(file_path, max_size) {
+        Ok(tmp_file_path) => tmp_file_path,
+        Err(e) => return (model, Err(e.into())),
+    };
+    let embedding = provider.generate_image_embedding(&tmp_file_path).await;
+    if let Err(e) = clear_temp_file(&tmp_file_path) {
+        return (model, Err(e.into()));
+    }
+    (model, embedding)
+}
+
+// Embedding of a search text in the space of the embeddings it is compared with
+pub async fn generate_query_embedding(provider: &dyn Provider, space: EmbeddingSpace, text: &str) -> (String, Result<Vec<f64>, Box<dyn Error>>) {
+    match space {
+        EmbeddingSpace::Description => generate_embedding(provider, text.to_string()).await,
+        EmbeddingSpace::Image => (provider.image_embedding_model().to_string(), provider.generate_image_text_embedding(text).await),
     }
 }
-async fn generate_embedding_openai(text: String) -> Result<Vec<f64>, Box<dyn Error>> {
-    let openai_client = crate::ai::openai::openai_client().await;
+
+pub async fn generate_embedding_openai(openai_client: &Client<OpenAIConfig>, model: &str, text: String) -> Result<Vec<f64>, Box<dyn Error>> {
     let request = CreateEmbeddingRequestArgs::default()
-        .model(OPENAI_MODEL_ID)
+        .model(model)
         .input(text)
         .build()?;
 
@@ -43,39 +58,87 @@ async fn generate_embedding_openai(text: String) -> Result<Vec<f64>, Box<dyn Err
             .collect(),
     );
 }
-async fn generate_embedding_bedrock(text: String) -> Result<Vec<f64>, Box<dyn Error>> {
-    let bedrock_client = bedrock_client().await;
-    let resp = bedrock_client.invoke_model()
-    .model_id(BEDROCK_MODEL_ID)
-    .body(Blob::new(
+pub async fn generate_embedding_bedrock(model: &str, text: String) -> Result<Vec<f64>, Box<dyn Error>> {
+    // Titan and Cohere models take different request bodies
+    let titan = model.starts_with("amazon.titan-embed");
+    let body = if titan {
+        serde_json::json!({ "inputText": text })
+    } else {
         serde_json::json!({
             "texts": vec![text],
             "input_type": "search_document" // "search_query"
-        }).to_string())
-    )
+        })
+    };
+
+    let bedrock_client = bedrock_client().await;
+    let resp = bedrock_client.invoke_model()
+    .model_id(model)
+    .body(Blob::new(body.to_string()))
+    .send()
+    .await;
+
+    let body = String::from_utf8(resp?.body().clone().into_inner())?;
+    let json = serde_json::from_str::<serde_json::Value>(&body)?;
+    let embeddings: Vec<f64> = if titan {
+        json.get("embedding")
+            .and_then(|v| v.as_array())
+            .ok_or("no embedding in response")?
+            .iter()
+            .filter_map(|v| v.as_f64())
+            .collect()
+    } else {
+        json.get("embeddings")
+            .and_then(|v| v.as_array())
+            .ok_or("no embeddings in response")?
+            .iter()
+            .filter_map(|v| v.as_array())
+            .flat_map(|v| v.iter())
+            .filter_map(|v| v.as_f64())
+            .collect()
+    };
+
+    Ok(embeddings)
+}
+
+// Titan Multimodal embeds images and texts into the same space
+pub async fn generate_embedding_bedrock_multimodal(model: &str, image_path: Option<&str>, text: Option<&str>) -> Result<Vec<f64>, Box<dyn Error>> {
+    let mut body = serde_json::json!({});
+    if let Some(image_path) = image_path {
+        body["inputImage"] = general_purpose::STANDARD.encode(std::fs::read(image_path)?).into();
+    }
+    if let Some(text) = text {
+        body["inputText"] = text.into();
+    }
+
+    let bedrock_client = bedrock_client().await;
+    let resp = bedrock_client.invoke_model()
+    .model_id(model)
+    .body(Blob::new(body.to_string()))
     .send()
     .await;
 
     let body = String::from_utf8(resp?.body().clone().into_inner())?;
     let json = serde_json::from_str::<serde_json::Value>(&body)?;
-    let embeddings = json
-        .as_object()
-        .unwrap()
-        .get("embeddings")
-        .unwrap()
-        .as_array()
-        .unwrap()
+    let embeddings: Vec<f64> = json.get("embedding")
+        .and_then(|v| v.as_array())
+        .ok_or("no embedding in response")?
         .iter()
-        .flat_map(|v| v.as_array().unwrap().iter())
         .filter_map(|v| v.as_f64())
         .collect();
 
     Ok(embeddings)
 }
 
-pub fn cosine_similarity(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+// 0 for vectors of different lengths, which come from different models and can't be compared
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
     let dot_product = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f64>();
     let norm_a = a.iter().map(|x| x * x).sum::<f64>().sqrt();
     let norm_b = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
     dot_product / (norm_a * norm_b)
 }
\ No newline at end of file
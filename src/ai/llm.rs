@@ -1,6 +1,7 @@
 use std::error::Error;
 use tokio::fs;
 
+use async_openai::{Client, config::OpenAIConfig};
 use async_openai::types::{ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, ImageDetail, ImageUrlArgs};
 use aws_sdk_bedrockruntime::{
     operation::converse::ConverseOutput,
@@ -10,12 +11,13 @@ use base64::{engine::general_purpose, Engine};
 
 use crate::ai::bedrock::bedrock_client;
 use crate::ai::bedrock::BedrockConverseError;
-use crate::ai::openai::openai_client;
+use crate::ai::provider::Provider;
 use crate::graphics::images::{path_to_bedrock_image_block, resize_temp_image, clear_temp_file};
 use crate::processing::metadata::PhotoMeta;
 
-const BEDROCK_MODEL_ID: &str = "anthropic.claude-3-5-sonnet-20241022-v2:0";
-const OPENAI_MODEL_ID: &str = "gpt-4o";
+// Defaults, override with --chat-model / --vision-model
+pub const DEFAULT_BEDROCK_MODEL_ID: &str = "anthropic.claude-3-5-sonnet-20241022-v2:0";
+pub const DEFAULT_OPENAI_MODEL_ID: &str = "gpt-4o";
 
 fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockConverseError> {
     let text = output
@@ -32,7 +34,7 @@ fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockCon
     Ok(text)
 }
 
-pub async fn describe_image(provider: &str, file_path: &str, _image_metadata: &PhotoMeta, prompt: &str) -> Result<String, Box<dyn Error>> {
+pub async fn describe_image(provider: &dyn Provider, file_path: &str, _image_metadata: &PhotoMeta, prompt: &str, max_size: u32) -> Result<String, Box<dyn Error>> {
     let content_text = if prompt.is_empty() {
         //let people = image_metadata.people.iter().fold("".to_string(), |acc, person| format!("{}<people>{}</people>", acc, person));
         // Claude appears to ignore people information provided in prompt TODO: Figure this out
@@ -66,18 +68,13 @@ pub async fn describe_image(provider: &str, file_path: &str, _image_metadata: &P
         prompt.to_string()
     };
 
-    // Get result based on provider
-    let tmp_file_path = resize_temp_image(file_path, 1000)?; // TODO: make a more scientific decision on the resizes
-    let result = match provider {
-        "bedrock" => describe_image_bedrock(&tmp_file_path, &content_text).await,
-        "openai" => describe_image_openai(&tmp_file_path, &content_text).await,
-        _ => return Err("Invalid provider".into()),
-    };
+    let tmp_file_path = resize_temp_image(file_path, max_size)?;
+    let result = provider.describe_image(&tmp_file_path, &content_text).await;
     clear_temp_file(&tmp_file_path)?;
-    return Ok(result.unwrap());
+    return result;
 }
 
-pub async fn describe_image_bedrock(tmp_file_path: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+pub async fn describe_image_bedrock(model: &str, tmp_file_path: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
     let message_user = Message::builder()
         .role(ConversationRole::User)
         .content(ContentBlock::Text(prompt.to_string()))
@@ -88,7 +85,7 @@ pub async fn describe_image_bedrock(tmp_file_path: &str, prompt: &str) -> Result
     let response = bedrock_client
         .converse()
         .messages(message_user)
-        .model_id(BEDROCK_MODEL_ID)
+        .model_id(model)
         .send()
         .await;
 
@@ -102,14 +99,13 @@ pub async fn describe_image_bedrock(tmp_file_path: &str, prompt: &str) -> Result
 }
 
 
-pub async fn describe_image_openai(tmp_file_path: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+pub async fn describe_image_openai(client: &Client<OpenAIConfig>, model: &str, tmp_file_path: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
     // Read the image file and encode as base64
     let image_bytes = fs::read(tmp_file_path).await?;
     let image_base64 = general_purpose::STANDARD.encode(&image_bytes);
 
-    let client = openai_client().await;
     let request = CreateChatCompletionRequestArgs::default()
-        .model(OPENAI_MODEL_ID)
+        .model(model)
         .max_tokens(4000_u32)
         .messages([ChatCompletionRequestUserMessageArgs::default()
             .content(vec![
@@ -139,7 +135,7 @@ pub async fn describe_image_openai(tmp_file_path: &str, prompt: &str) -> Result<
 }
 
 
-pub async fn tag_metadata(_provider: &str, metadata: &PhotoMeta, tags: &Vec<String>) -> Result<String, Box<dyn Error>> {
+pub async fn tag_metadata(provider: &dyn Provider, metadata: &PhotoMeta, tags: &Vec<String>) -> Result<String, Box<dyn Error>> {
     let labels = tags.iter().fold("".to_string(), |acc, tag| format!("{}<label>{}</label>", acc, tag));
     let tagged_people = metadata.people.iter().fold("".to_string(), |acc, person| format!("{}<person>{}</person>", acc, person));
 
@@ -155,7 +151,7 @@ pub async fn tag_metadata(_provider: &str, metadata: &PhotoMeta, tags: &Vec<Stri
         <description>{}</description>
         <labels>{}</labels>", tagged_people, metadata.description, labels
     );
-    let result = converse(&prompt).await
+    let result = provider.complete(&prompt).await
     .map(|response| response.replace("<label>", "").replace("</label>", ""));
 
     // Check if the response is in the list of tags
@@ -171,11 +167,28 @@ pub async fn tag_metadata(_provider: &str, metadata: &PhotoMeta, tags: &Vec<Stri
     }
 }
 
-pub async fn converse(content: &str) -> Result<String, Box<dyn Error>> {
+// Short album name for a group of images, from the descriptions of a few of them
+pub async fn name_cluster(provider: &dyn Provider, descriptions: &[&str]) -> Result<String, Box<dyn Error>> {
+    let descriptions = descriptions.iter().fold("".to_string(), |acc, description| format!("{}<description>{}</description>", acc, description));
+
+    let prompt = format!(
+        "
+        You are naming a photo album.
+        You will be given descriptions of some of the photos in it.
+        You will give the album a short name of at most four words, describing what the photos have in common.
+        Return the name only in <name></name>.
+
+        <descriptions>{}</descriptions>", descriptions
+    );
+    let response = provider.complete(&prompt).await?;
+    Ok(response.replace("<name>", "").replace("</name>", "").trim().to_string())
+}
+
+pub async fn converse(model: &str, content: &str) -> Result<String, Box<dyn Error>> {
     let bedrock_client = bedrock_client().await;
     let response = bedrock_client
         .converse()
-        .model_id(BEDROCK_MODEL_ID)
+        .model_id(model)
         .messages(
             Message::builder()
                 .role(ConversationRole::User)
@@ -193,4 +206,21 @@ pub async fn converse(content: &str) -> Result<String, Box<dyn Error>> {
         }
         Err(e) => Err(Box::new(e)),
     }
-}
\ No newline at end of file
+}
+
+pub async fn converse_openai(client: &Client<OpenAIConfig>, model: &str, content: &str) -> Result<String, Box<dyn Error>> {
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .max_tokens(4000_u32)
+        .messages([ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()?
+            .into()])
+        .build()?;
+
+    let response = client.chat().create(request).await?;
+    let choice = response.choices.get(0).ok_or("no choices")?;
+    let message = choice.message.content.clone().unwrap_or_default();
+
+    Ok(message)
+}
@@ -0,0 +1,394 @@
+use async_trait::async_trait;
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use ndarray::Array4;
+use ort::session::Session;
+use ort::value::Tensor;
+use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::ai::provider::{Capability, Provider, ProviderConfig};
+use crate::processing::cluster;
+use crate::processing::ann::{self, Hnsw};
+use crate::processing::config;
+use crate::processing::faces::DiscoveredFace;
+use crate::processing::metadata::{BoundingBox, FaceRegion, FaceSource};
+use crate::processing::util;
+
+// Looked for in ~/.config/glimpse/models when no model path is configured
+pub const DEFAULT_DETECTOR_MODEL: &str = "version-RFB-320.onnx";
+pub const DEFAULT_EMBEDDING_MODEL: &str = "w600k_mbf.onnx";
+
+// UltraFace RFB-320 input size and normalization
+const DETECTOR_WIDTH: u32 = 320;
+const DETECTOR_HEIGHT: u32 = 240;
+const DETECTOR_MEAN: f32 = 127.0;
+const DETECTOR_SCALE: f32 = 1.0 / 128.0;
+const DETECTION_SCORE: f32 = 0.7;
+const NMS_OVERLAP: f32 = 0.3;
+
+// ArcFace input size and normalization
+const EMBEDDING_SIZE: u32 = 112;
+const EMBEDDING_MEAN: f32 = 127.5;
+const EMBEDDING_SCALE: f32 = 1.0 / 127.5;
+// Faces are cropped square with some context, close to the aligned crops ArcFace was trained on
+const EMBEDDING_MARGIN: f32 = 0.1;
+
+// ArcFace cosines of the same person usually start around MATCH_COSINE and rarely pass CERTAIN_COSINE.
+// They are stretched onto 0-100 with MATCH_COSINE on MATCH_SIMILARITY, the similarity Rekognition
+// starts matching the same person at.
+const MATCH_COSINE: f32 = 0.35;
+const CERTAIN_COSINE: f32 = 0.7;
+const MATCH_SIMILARITY: f32 = 85.0;
+
+// Nearest faces each face is compared with by discover-faces, a person's faces beyond them are still
+// grouped through the faces they share
+const DISCOVER_NEIGHBOURS: usize = 32;
+
+// Local face collections, one JSON file per collection
+const COLLECTIONS_DIRECTORY: &str = "faces";
+const MODELS_DIRECTORY: &str = "models";
+
+// Every face of an image with its embedding
+type FaceEmbeddings = Vec<(BoundingBox, Vec<f32>)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EnrolledFace {
+    person: String,
+    embedding: Vec<f32>,
+}
+
+// Runs an ONNX face detector and face embedding model on the CPU in place of the provider's face
+// methods, everything else is left to the wrapped provider. Images never leave the machine.
+pub struct LocalFaceProvider {
+    provider: Box<dyn Provider>,
+    capabilities: Vec<Capability>,
+    detector_model: PathBuf,
+    embedding_model: PathBuf,
+    // Loaded on first use, so actions without faces don't need the models
+    detector: OnceCell<Session>,
+    embedder: OnceCell<Session>,
+}
+
+impl LocalFaceProvider {
+    pub fn new(provider: Box<dyn Provider>, config: &ProviderConfig) -> Self {
+        let mut capabilities: Vec<Capability> = provider
+            .capabilities()
+            .iter()
+            .copied()
+            .filter(|capability| !matches!(capability, Capability::CompareFaces | Capability::FaceCollection | Capability::DiscoverFaces))
+            .collect();
+        capabilities.extend([Capability::CompareFaces, Capability::FaceCollection, Capability::DiscoverFaces]);
+        LocalFaceProvider {
+            provider,
+            capabilities,
+            detector_model: model_path(&config.face_detector_model, DEFAULT_DETECTOR_MODEL),
+            embedding_model: model_path(&config.face_embedding_model, DEFAULT_EMBEDDING_MODEL),
+            detector: OnceCell::new(),
+            embedder: OnceCell::new(),
+        }
+    }
+
+    fn detector(&self) -> Result<&Session, Box<dyn Error>> {
+        load_session(&self.detector, &self.detector_model, "--face-detector-model")
+    }
+
+    fn embedder(&self) -> Result<&Session, Box<dyn Error>> {
+        load_session(&self.embedder, &self.embedding_model, "--face-embedding-model")
+    }
+
+    // Faces in an image, left to right
+    fn detect(&self, img: &DynamicImage) -> Result<Vec<BoundingBox>, Box<dyn Error>> {
+        let resized = img.resize_exact(DETECTOR_WIDTH, DETECTOR_HEIGHT, FilterType::Triangle).to_rgb8();
+        let input = Array4::from_shape_fn((1, 3, DETECTOR_HEIGHT as usize, DETECTOR_WIDTH as usize), |(_, c, y, x)| {
+            (resized.get_pixel(x as u32, y as u32)[c] as f32 - DETECTOR_MEAN) * DETECTOR_SCALE
+        });
+        let outputs = self.detector()?.run(ort::inputs![Tensor::from_array(input)?]?)?;
+        let scores = outputs["scores"].try_extract_tensor::<f32>()?;
+        let boxes = outputs["boxes"].try_extract_tensor::<f32>()?;
+        // Scores are [1, N, 2] (background, face) and boxes [1, N, 4], anything else isn't an UltraFace model
+        let count = match (scores.shape(), boxes.shape()) {
+            ([1, count, 2], [1, box_count, 4]) if count == box_count => *count,
+            (scores, boxes) => {
+                return Err(format!("{} is not a face detector, it returned scores {:?} and boxes {:?}", self.detector_model.display(), scores, boxes).into())
+            }
+        };
+
+        // Boxes are normalized corners, the same on the resized image as on the original
+        let mut candidates = vec![];
+        for i in 0..count {
+            let score = scores[[0, i, 1]];
+            if score < DETECTION_SCORE {
+                continue;
+            }
+            let (x0, y0) = (boxes[[0, i, 0]].clamp(0.0, 1.0), boxes[[0, i, 1]].clamp(0.0, 1.0));
+            let (x1, y1) = (boxes[[0, i, 2]].clamp(0.0, 1.0), boxes[[0, i, 3]].clamp(0.0, 1.0));
+            if x1 > x0 && y1 > y0 {
+                candidates.push((BoundingBox { left: x0, top: y0, width: x1 - x0, height: y1 - y0 }, score));
+            }
+        }
+        let mut faces = non_maximum_suppression(candidates);
+        faces.sort_by(|a, b| a.left.total_cmp(&b.left));
+        Ok(faces)
+    }
+
+    // Unit length ArcFace embedding of a face, so the dot product of two is their cosine
+    fn embed(&self, img: &DynamicImage, face: &BoundingBox) -> Result<Vec<f32>, Box<dyn Error>> {
+        let crop = square_crop(img, face)
+            .resize_exact(EMBEDDING_SIZE, EMBEDDING_SIZE, FilterType::Triangle)
+            .to_rgb8();
+        let input = Array4::from_shape_fn((1, 3, EMBEDDING_SIZE as usize, EMBEDDING_SIZE as usize), |(_, c, y, x)| {
+            (crop.get_pixel(x as u32, y as u32)[c] as f32 - EMBEDDING_MEAN) * EMBEDDING_SCALE
+        });
+        let outputs = self.embedder()?.run(ort::inputs![Tensor::from_array(input)?]?)?;
+        let output = outputs[0].try_extract_tensor::<f32>()?;
+        // One embedding of the one face, [1, D]
+        if !matches!(output.shape(), [1, dimension] if *dimension > 0) {
+            return Err(format!("{} is not a face embedding model, it returned {:?}", self.embedding_model.display(), output.shape()).into());
+        }
+        let mut embedding: Vec<f32> = output.iter().copied().collect();
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|v| *v /= norm);
+        }
+        Ok(embedding)
+    }
+
+    fn embed_faces(&self, image_path: &str) -> Result<FaceEmbeddings, Box<dyn Error>> {
+        let img = image::open(image_path)?;
+        let mut faces = vec![];
+        for face in self.detect(&img)? {
+            faces.push((face, self.embed(&img, &face)?));
+        }
+        Ok(faces)
+    }
+
+    // Embedding of the largest face, the one a reference image is about
+    fn embed_largest_face(&self, image_path: &str) -> Result<Option<Vec<f32>>, Box<dyn Error>> {
+        Ok(self
+            .embed_faces(image_path)?
+            .into_iter()
+            .max_by(|a, b| (a.0.width * a.0.height).total_cmp(&(b.0.width * b.0.height)))
+            .map(|(_face, embedding)| embedding))
+    }
+}
+
+fn model_path(configured: &Option<String>, default: &str) -> PathBuf {
+    match configured {
+        Some(path) => PathBuf::from(path),
+        None => config::user_config_directory().unwrap_or_default().join(MODELS_DIRECTORY).join(default),
+    }
+}
+
+fn load_session<'a>(cell: &'a OnceCell<Session>, path: &PathBuf, option: &str) -> Result<&'a Session, Box<dyn Error>> {
+    if let Some(session) = cell.get() {
+        return Ok(session);
+    }
+    if !path.is_file() {
+        return Err(format!("Face model {} not found, download it there or pass {}", path.display(), option).into());
+    }
+    let session = Session::builder()?.commit_from_file(path)?;
+    Ok(cell.get_or_init(|| session))
+}
+
+fn overlap(a: &BoundingBox, b: &BoundingBox) -> f32 {
+    let width = (a.left + a.width).min(b.left + b.width) - a.left.max(b.left);
+    let height = (a.top + a.height).min(b.top + b.height) - a.top.max(b.top);
+    if width <= 0.0 || height <= 0.0 {
+        return 0.0;
+    }
+    let intersection = width * height;
+    intersection / (a.width * a.height + b.width * b.height - intersection)
+}
+
+// The detector reports each face many times, keep the best scoring box of each overlapping set
+fn non_maximum_suppression(mut candidates: Vec<(BoundingBox, f32)>) -> Vec<BoundingBox> {
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut faces: Vec<BoundingBox> = vec![];
+    for (candidate, _score) in candidates {
+        if faces.iter().all(|face| overlap(face, &candidate) < NMS_OVERLAP) {
+            faces.push(candidate);
+        }
+    }
+    faces
+}
+
+// A square around the face, widened by the margin and kept within the image
+fn square_crop(img: &DynamicImage, face: &BoundingBox) -> DynamicImage {
+    let (image_width, image_height) = img.dimensions();
+    let center_x = (face.left + face.width / 2.0) * image_width as f32;
+    let center_y = (face.top + face.height / 2.0) * image_height as f32;
+    let side = (face.width * image_width as f32).max(face.height * image_height as f32) * (1.0 + 2.0 * EMBEDDING_MARGIN);
+    let x0 = (center_x - side / 2.0).max(0.0) as u32;
+    let y0 = (center_y - side / 2.0).max(0.0) as u32;
+    let x1 = ((center_x + side / 2.0).min(image_width as f32) as u32).max(x0 + 1);
+    let y1 = ((center_y + side / 2.0).min(image_height as f32) as u32).max(y0 + 1);
+    img.crop_imm(x0, y0, x1 - x0, y1 - y0)
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// Cosine of two face embeddings as a 0-100 similarity
+fn similarity(cosine: f32) -> f32 {
+    if cosine < MATCH_COSINE {
+        cosine.max(0.0) / MATCH_COSINE * MATCH_SIMILARITY
+    } else {
+        (MATCH_SIMILARITY + (cosine - MATCH_COSINE) / (CERTAIN_COSINE - MATCH_COSINE) * (100.0 - MATCH_SIMILARITY)).min(100.0)
+    }
+}
+
+fn collection_path(collection: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let directory = config::user_config_directory().ok_or("No config directory to keep face collections in, set HOME")?;
+    // Encoded so a collection name can't point outside the directory
+    Ok(directory.join(COLLECTIONS_DIRECTORY).join(format!("{}.json", util::encode_name(collection))))
+}
+
+fn read_collection(collection: &str) -> Result<Vec<EnrolledFace>, Box<dyn Error>> {
+    let path = collection_path(collection)?;
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+fn write_collection(collection: &str, faces: &[EnrolledFace]) -> Result<(), Box<dyn Error>> {
+    let path = collection_path(collection)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string(faces)?)?;
+    Ok(())
+}
+
+#[async_trait(?Send)]
+impl Provider for LocalFaceProvider {
+    fn name(&self) -> &str {
+        self.provider.name()
+    }
+
+    fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    async fn describe_image(&self, image_path: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.provider.describe_image(image_path, prompt).await
+    }
+
+    async fn complete(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        self.provider.complete(prompt).await
+    }
+
+    fn embedding_model(&self) -> &str {
+        self.provider.embedding_model()
+    }
+
+    async fn generate_embedding(&self, text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        self.provider.generate_embedding(text).await
+    }
+
+    fn image_embedding_model(&self) -> &str {
+        self.provider.image_embedding_model()
+    }
+
+    async fn generate_image_embedding(&self, image_path: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        self.provider.generate_image_embedding(image_path).await
+    }
+
+    async fn generate_image_text_embedding(&self, text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        self.provider.generate_image_text_embedding(text).await
+    }
+
+    fn face_source(&self) -> Option<FaceSource> {
+        Some(FaceSource::Local)
+    }
+
+    async fn detect_faces(&self, image_path: &str) -> Result<Vec<BoundingBox>, Box<dyn Error>> {
+        self.detect(&image::open(image_path)?)
+    }
+
+    async fn compare_faces(&self, reference_file: &str, target_file: &str) -> Result<Vec<(BoundingBox, f32)>, Box<dyn Error>> {
+        let reference = self
+            .embed_largest_face(reference_file)?
+            .ok_or(format!("No face found in {}", reference_file))?;
+        Ok(self
+            .embed_faces(target_file)?
+            .into_iter()
+            .map(|(face, embedding)| (face, similarity(cosine(&reference, &embedding))))
+            .collect())
+    }
+
+    async fn enroll_face(&self, collection: &str, person_name: &str, image_path: &str) -> Result<usize, Box<dyn Error>> {
+        let embedding = match self.embed_largest_face(image_path)? {
+            Some(embedding) => embedding,
+            None => return Ok(0),
+        };
+        let mut faces = read_collection(collection)?;
+        faces.push(EnrolledFace { person: person_name.to_string(), embedding });
+        write_collection(collection, &faces)?;
+        Ok(1)
+    }
+
+    // Each face is matched with its closest enrolled face, a person found twice keeps the better match
+    async fn search_faces(&self, collection: &str, image_path: &str, threshold: f32) -> Result<Vec<FaceRegion>, Box<dyn Error>> {
+        let enrolled = read_collection(collection)?;
+        if enrolled.is_empty() {
+            return Err(format!("No one is enrolled in the local face collection {}, run enroll-person first", collection).into());
+        }
+        let mut people: Vec<FaceRegion> = vec![];
+        for (face, embedding) in self.embed_faces(image_path)? {
+            let best = enrolled
+                .iter()
+                .map(|enrolled_face| (enrolled_face, similarity(cosine(&enrolled_face.embedding, &embedding))))
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            let (person_name, similarity) = match best {
+                Some((enrolled_face, similarity)) if similarity >= threshold => (&enrolled_face.person, similarity),
+                _ => continue,
+            };
+            match people.iter_mut().find(|region| &region.person == person_name) {
+                Some(region) if region.similarity >= similarity => {}
+                Some(region) => *region = FaceRegion::new(person_name, face, similarity, FaceSource::Local, collection),
+                None => people.push(FaceRegion::new(person_name, face, similarity, FaceSource::Local, collection)),
+            }
+        }
+        Ok(people)
+    }
+
+    async fn discover_faces(&self, image_paths: &[String], threshold: f32) -> Result<Vec<Vec<DiscoveredFace>>, Box<dyn Error>> {
+        // A missing model would otherwise fail every image, and only be logged
+        self.detector()?;
+        self.embedder()?;
+        let mut faces: Vec<(DiscoveredFace, Vec<f32>)> = vec![];
+        for image_path in image_paths {
+            match self.embed_faces(image_path) {
+                Ok(image_faces) => faces.extend(image_faces.into_iter().map(|(bounding_box, embedding)| {
+                    (DiscoveredFace { file: image_path.clone(), bounding_box }, embedding)
+                })),
+                Err(e) => println!("Failed to find faces in {}: {:?}", image_path, e),
+            }
+        }
+        // Each face is only compared with its nearest neighbours in a vector index, not every other face
+        let vectors: Vec<Vec<f64>> = faces
+            .iter()
+            .map(|(_face, embedding)| embedding.iter().map(|x| *x as f64).collect())
+            .collect();
+        let mut index = Hnsw::new(vectors.first().map_or(0, |vector| vector.len()));
+        for vector in &vectors {
+            index.insert(vector)?;
+        }
+        let mut links = vec![];
+        for (i, vector) in vectors.iter().enumerate() {
+            for (j, _distance) in ann::search(&index, vector, DISCOVER_NEIGHBOURS, DISCOVER_NEIGHBOURS)? {
+                if j != i && similarity(cosine(&faces[i].1, &faces[j].1)) >= threshold {
+                    links.push((i, j));
+                }
+            }
+        }
+        Ok(cluster::connected_groups(faces.len(), links)
+            .into_iter()
+            .map(|group| group.into_iter().map(|i| faces[i].0.clone()).collect())
+            .collect())
+    }
+}
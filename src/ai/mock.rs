@@ -0,0 +1,240 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::ai::provider::{Capability, Provider, ProviderConfig};
+use crate::processing::cluster;
+use crate::processing::config;
+use crate::processing::faces::DiscoveredFace;
+use crate::processing::metadata::{BoundingBox, FaceRegion, FaceSource};
+use crate::processing::util::fnv1a;
+use crate::graphics::images;
+
+pub const DEFAULT_EMBEDDING_MODEL_ID: &str = "mock-embedding";
+pub const DEFAULT_IMAGE_EMBEDDING_MODEL_ID: &str = "mock-image-embedding";
+const EMBEDDING_DIMENSIONS: usize = 64;
+
+// Mock face collections, one JSON file of enrolled image hashes per collection
+const COLLECTIONS_DIRECTORY: &str = "mock-faces";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EnrolledFace {
+    person: String,
+    hash: u64,
+}
+
+// Deterministic provider for offline testing. Every result is derived from the image content or
+// the input text, so the same inputs always produce the same outputs and no network is used.
+pub struct MockProvider {
+    // Only recorded, lets tests exercise mismatched embedding models
+    embedding_model: String,
+    image_embedding_model: String,
+}
+
+impl MockProvider {
+    pub fn new(config: &ProviderConfig) -> Self {
+        MockProvider {
+            embedding_model: config.embedding_model(DEFAULT_EMBEDDING_MODEL_ID),
+            image_embedding_model: config.image_embedding_model(DEFAULT_IMAGE_EMBEDDING_MODEL_ID),
+        }
+    }
+}
+
+fn color_name(r: u8, g: u8, b: u8) -> &'static str {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    if max - min < 24 {
+        return "gray";
+    }
+    if max == r {
+        "red"
+    } else if max == g {
+        "green"
+    } else {
+        "blue"
+    }
+}
+
+fn brightness_name(r: u8, g: u8, b: u8) -> &'static str {
+    match (r as u32 + g as u32 + b as u32) / 3 {
+        0..=85 => "dark",
+        86..=170 => "balanced",
+        _ => "bright",
+    }
+}
+
+// Whole image "faces" compared by the hamming distance between their average hashes, as a 0-100 similarity
+fn face_similarity(a: u64, b: u64) -> f32 {
+    100.0 * (1.0 - (a ^ b).count_ones() as f32 / 64.0)
+}
+
+fn collection_path(collection: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let directory = config::user_config_directory().ok_or("No config directory to keep face collections in, set HOME")?;
+    Ok(directory.join(COLLECTIONS_DIRECTORY).join(format!("{}.json", collection)))
+}
+
+fn read_collection(collection: &str) -> Result<Vec<EnrolledFace>, Box<dyn Error>> {
+    let path = collection_path(collection)?;
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+fn write_collection(collection: &str, faces: &[EnrolledFace]) -> Result<(), Box<dyn Error>> {
+    let path = collection_path(collection)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string(faces)?)?;
+    Ok(())
+}
+
+// Text between the first <tag> and </tag>
+fn extract_tag<'a>(text: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = text.find(&open)? + open.len();
+    let end = text[start..].find(&close)? + start;
+    Some(&text[start..end])
+}
+
+#[async_trait(?Send)]
+impl Provider for MockProvider {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn capabilities(&self) -> &[Capability] {
+        &[Capability::DescribeImage, Capability::Complete, Capability::Embed, Capability::ImageEmbed, Capability::CompareFaces, Capability::FaceCollection, Capability::DiscoverFaces]
+    }
+
+    async fn describe_image(&self, image_path: &str, _prompt: &str) -> Result<String, Box<dyn Error>> {
+        let (r, g, b) = images::average_color(image_path)?;
+        let hash = images::average_hash(image_path)?;
+        Ok(format!(
+            "A {} image that is mostly {}. Fingerprint {:016x}.",
+            brightness_name(r, g, b),
+            color_name(r, g, b),
+            hash
+        ))
+    }
+
+    // Answers labeling prompts with the first label mentioned in the description (or the first
+    // label if none is), and anything else with a hash of the prompt.
+    async fn complete(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(labels) = extract_tag(prompt, "labels") {
+            let description = extract_tag(prompt, "description").unwrap_or("").to_lowercase();
+            let labels: Vec<&str> = labels
+                .split("<label>")
+                .filter_map(|label| label.strip_suffix("</label>"))
+                .collect();
+            let label = labels
+                .iter()
+                .find(|label| description.contains(&label.to_lowercase()))
+                .or(labels.first());
+            if let Some(label) = label {
+                return Ok(format!("<label>{}</label>", label));
+            }
+        }
+        Ok(format!("mock-{:016x}", fnv1a(prompt.as_bytes())))
+    }
+
+    fn embedding_model(&self) -> &str {
+        &self.embedding_model
+    }
+
+    // Hashed bag of words, so texts sharing words end up close together
+    async fn generate_embedding(&self, text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut embedding = vec![0.0; EMBEDDING_DIMENSIONS];
+        for word in text.to_lowercase().split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+            let hash = fnv1a(word.as_bytes());
+            let sign = if hash & (1 << 63) == 0 { 1.0 } else { -1.0 };
+            embedding[(hash % EMBEDDING_DIMENSIONS as u64) as usize] += sign;
+        }
+
+        let norm = embedding.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        Ok(embedding)
+    }
+
+    fn image_embedding_model(&self) -> &str {
+        &self.image_embedding_model
+    }
+
+    // Embedding of the mock description, so text queries naming a color or brightness land close
+    async fn generate_image_embedding(&self, image_path: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        let description = self.describe_image(image_path, "").await?;
+        self.generate_embedding(&description).await
+    }
+
+    async fn generate_image_text_embedding(&self, text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        self.generate_embedding(text).await
+    }
+
+    fn face_source(&self) -> Option<FaceSource> {
+        Some(FaceSource::Mock)
+    }
+
+    // The whole image is a single face
+    async fn detect_faces(&self, _image_path: &str) -> Result<Vec<BoundingBox>, Box<dyn Error>> {
+        Ok(vec![BoundingBox::FULL])
+    }
+
+    async fn compare_faces(&self, reference_file: &str, target_file: &str) -> Result<Vec<(BoundingBox, f32)>, Box<dyn Error>> {
+        let similarity = face_similarity(images::average_hash(reference_file)?, images::average_hash(target_file)?);
+        Ok(vec![(BoundingBox::FULL, similarity)])
+    }
+
+    async fn enroll_face(&self, collection: &str, person_name: &str, image_path: &str) -> Result<usize, Box<dyn Error>> {
+        let mut faces = read_collection(collection)?;
+        faces.push(EnrolledFace { person: person_name.to_string(), hash: images::average_hash(image_path)? });
+        write_collection(collection, &faces)?;
+        Ok(1)
+    }
+
+    // The image is tagged with the enrolled person it is most similar to
+    async fn search_faces(&self, collection: &str, image_path: &str, threshold: f32) -> Result<Vec<FaceRegion>, Box<dyn Error>> {
+        let enrolled = read_collection(collection)?;
+        if enrolled.is_empty() {
+            return Err(format!("No one is enrolled in the mock face collection {}, run enroll-person first", collection).into());
+        }
+        let hash = images::average_hash(image_path)?;
+        Ok(enrolled
+            .iter()
+            .map(|face| (face, face_similarity(face.hash, hash)))
+            .filter(|(_face, similarity)| *similarity >= threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(face, similarity)| FaceRegion::new(&face.person, BoundingBox::FULL, similarity, FaceSource::Mock, collection))
+            .into_iter()
+            .collect())
+    }
+
+    // Images are linked when their whole image "faces" compare above the threshold
+    async fn discover_faces(&self, image_paths: &[String], threshold: f32) -> Result<Vec<Vec<DiscoveredFace>>, Box<dyn Error>> {
+        let mut hashes = vec![];
+        for image_path in image_paths {
+            hashes.push(images::average_hash(image_path)?);
+        }
+        let mut links = vec![];
+        for i in 0..hashes.len() {
+            for j in i + 1..hashes.len() {
+                if face_similarity(hashes[i], hashes[j]) >= threshold {
+                    links.push((i, j));
+                }
+            }
+        }
+        Ok(cluster::connected_groups(image_paths.len(), links)
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|i| DiscoveredFace { file: image_paths[i].clone(), bounding_box: BoundingBox::FULL })
+                    .collect()
+            })
+            .collect())
+    }
+}
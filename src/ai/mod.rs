@@ -2,4 +2,8 @@ pub mod vision;
 pub mod llm;
 pub mod openai;
 pub mod bedrock;
-pub mod embedding;
\ No newline at end of file
+pub mod embedding;
+pub mod mock;
+#[cfg(feature = "local-faces")]
+pub mod local_faces;
+pub mod provider;
\ No newline at end of file
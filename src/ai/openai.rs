@@ -1,7 +1,88 @@
 use async_openai::{Client, config::OpenAIConfig};
+use async_trait::async_trait;
+use std::error::Error;
+
+use crate::ai::{embedding, llm};
+use crate::ai::provider::{Capability, Provider, ProviderConfig};
+use crate::processing::metadata::FaceSource;
 
 pub async fn openai_client() -> Client<OpenAIConfig> {
     let config = OpenAIConfig::default();
     return Client::with_config(config);
 }
 
+// Client for self-hosted servers exposing the OpenAI API (ollama, llama.cpp server, vLLM, ...)
+pub fn openai_compatible_client(base_url: &str, api_key: &str) -> Client<OpenAIConfig> {
+    // Always set the key so OPENAI_API_KEY is never sent to a third party server
+    let config = OpenAIConfig::new()
+        .with_api_base(base_url)
+        .with_api_key(api_key);
+    Client::with_config(config)
+}
+
+// OpenAI has no face recognition, so compare-faces is not supported
+pub struct OpenAIProvider {
+    name: String,
+    client: Client<OpenAIConfig>,
+    chat_model: String,
+    vision_model: String,
+    embedding_model: String,
+}
+
+// Defaults for the openai-compatible provider, models commonly pulled into ollama
+const DEFAULT_COMPATIBLE_CHAT_MODEL_ID: &str = "llava";
+const DEFAULT_COMPATIBLE_EMBEDDING_MODEL_ID: &str = "nomic-embed-text";
+
+impl OpenAIProvider {
+    pub async fn new(config: &ProviderConfig) -> Self {
+        OpenAIProvider {
+            name: "openai".to_string(),
+            client: openai_client().await,
+            chat_model: config.chat_model(llm::DEFAULT_OPENAI_MODEL_ID),
+            vision_model: config.vision_model(llm::DEFAULT_OPENAI_MODEL_ID),
+            embedding_model: config.embedding_model(embedding::DEFAULT_OPENAI_MODEL_ID),
+        }
+    }
+
+    pub fn compatible(config: &ProviderConfig) -> Self {
+        OpenAIProvider {
+            name: "openai-compatible".to_string(),
+            client: openai_compatible_client(&config.base_url, &config.api_key),
+            chat_model: config.chat_model(DEFAULT_COMPATIBLE_CHAT_MODEL_ID),
+            vision_model: config.vision_model(DEFAULT_COMPATIBLE_CHAT_MODEL_ID),
+            embedding_model: config.embedding_model(DEFAULT_COMPATIBLE_EMBEDDING_MODEL_ID),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for OpenAIProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn capabilities(&self) -> &[Capability] {
+        &[Capability::DescribeImage, Capability::Complete, Capability::Embed]
+    }
+
+    async fn describe_image(&self, image_path: &str, prompt: &str) -> Result<String, Box<dyn Error>> {
+        llm::describe_image_openai(&self.client, &self.vision_model, image_path, prompt).await
+    }
+
+    async fn complete(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        llm::converse_openai(&self.client, &self.chat_model, prompt).await
+    }
+
+    fn embedding_model(&self) -> &str {
+        &self.embedding_model
+    }
+
+    async fn generate_embedding(&self, text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        embedding::generate_embedding_openai(&self.client, &self.embedding_model, text.to_string()).await
+    }
+
+    // Faces are only recognized through the local face backend
+    fn face_source(&self) -> Option<FaceSource> {
+        None
+    }
+}
@@ -0,0 +1,209 @@
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::error::Error;
+
+use crate::ai::bedrock::BedrockProvider;
+#[cfg(feature = "local-faces")]
+use crate::ai::local_faces::LocalFaceProvider;
+use crate::ai::mock::MockProvider;
+use crate::ai::openai::OpenAIProvider;
+use crate::processing::faces::DiscoveredFace;
+use crate::processing::metadata::{BoundingBox, FaceRegion, FaceSource};
+
+// Actions a provider may or may not be able to perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    DescribeImage,
+    Complete,
+    Embed,
+    ImageEmbed,
+    CompareFaces,
+    FaceCollection,
+    DiscoverFaces,
+}
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::DescribeImage => "image description",
+            Capability::Complete => "text completion",
+            Capability::Embed => "embeddings",
+            Capability::ImageEmbed => "image embeddings",
+            Capability::CompareFaces => "face comparison",
+            Capability::FaceCollection => "face collections",
+            Capability::DiscoverFaces => "face discovery",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+pub struct UnsupportedCapabilityError {
+    pub provider: String,
+    pub capability: Capability,
+}
+impl std::fmt::Display for UnsupportedCapabilityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Provider {} does not support {}", self.provider, self.capability)
+    }
+}
+impl std::error::Error for UnsupportedCapabilityError {}
+
+// A backend able to perform some or all of the AI tasks glimpse needs.
+// Methods for capabilities the backend does not declare fail with UnsupportedCapabilityError.
+#[async_trait(?Send)]
+pub trait Provider {
+    fn name(&self) -> &str;
+    fn capabilities(&self) -> &[Capability];
+
+    fn supports(&self, capability: Capability) -> bool {
+        self.capabilities().contains(&capability)
+    }
+
+    // Check all capabilities up front so actions fail before touching any file
+    fn require(&self, capabilities: &[Capability]) -> Result<(), UnsupportedCapabilityError> {
+        for capability in capabilities {
+            if !self.supports(*capability) {
+                return Err(self.unsupported(*capability));
+            }
+        }
+        Ok(())
+    }
+
+    fn unsupported(&self, capability: Capability) -> UnsupportedCapabilityError {
+        UnsupportedCapabilityError {
+            provider: self.name().to_string(),
+            capability,
+        }
+    }
+
+    // Describe an (already resized) image using the given prompt
+    async fn describe_image(&self, _image_path: &str, _prompt: &str) -> Result<String, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::DescribeImage)))
+    }
+
+    async fn complete(&self, _prompt: &str) -> Result<String, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::Complete)))
+    }
+
+    // Model id recorded alongside generated embeddings
+    fn embedding_model(&self) -> &str {
+        ""
+    }
+
+    async fn generate_embedding(&self, _text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::Embed)))
+    }
+
+    // Model id recorded alongside generated image embeddings
+    fn image_embedding_model(&self) -> &str {
+        ""
+    }
+
+    // Embed an (already resized) image with a multimodal model
+    async fn generate_image_embedding(&self, _image_path: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::ImageEmbed)))
+    }
+
+    // Embed a text into the same space as the images, for text to image search
+    async fn generate_image_text_embedding(&self, _text: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::ImageEmbed)))
+    }
+
+    // Recorded with the faces it recognizes, None when it can't recognize faces
+    fn face_source(&self) -> Option<FaceSource>;
+
+    // Faces in an image, left to right
+    async fn detect_faces(&self, _image_path: &str) -> Result<Vec<BoundingBox>, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::CompareFaces)))
+    }
+
+    // Every face of the target compared with the largest face of the reference, with its similarity (0-100)
+    async fn compare_faces(&self, _reference_file: &str, _target_file: &str) -> Result<Vec<(BoundingBox, f32)>, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::CompareFaces)))
+    }
+
+    // Add the face in a reference image to a named collection under a person, returns the number of
+    // faces added
+    async fn enroll_face(&self, _collection: &str, _person_name: &str, _image_path: &str) -> Result<usize, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::FaceCollection)))
+    }
+
+    // Faces of the collection's people found in an image, with a similarity (0-100) of at least threshold
+    async fn search_faces(&self, _collection: &str, _image_path: &str, _threshold: f32) -> Result<Vec<FaceRegion>, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::FaceCollection)))
+    }
+
+    // Every face in the images, grouped with the faces it matches with a similarity (0-100) of at
+    // least threshold, directly or through other faces
+    async fn discover_faces(&self, _image_paths: &[String], _threshold: f32) -> Result<Vec<Vec<DiscoveredFace>>, Box<dyn Error>> {
+        Err(Box::new(self.unsupported(Capability::DiscoverFaces)))
+    }
+}
+
+// What detects and recognizes faces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FaceBackend {
+    // The provider's own face service, Rekognition on bedrock
+    #[default]
+    Provider,
+    // ONNX models run on the CPU, images never leave the machine
+    Local,
+}
+
+// Settings used to construct a provider
+#[derive(Debug, Clone, Default)]
+pub struct ProviderConfig {
+    pub name: String,
+    // Only used by the openai-compatible provider
+    pub base_url: String,
+    pub api_key: String,
+    // Model ids, each provider falls back to its own default when unset
+    pub chat_model: Option<String>,
+    pub vision_model: Option<String>,
+    pub embedding_model: Option<String>,
+    pub image_embedding_model: Option<String>,
+    // Longest side of images sent to the provider
+    pub max_image_size: u32,
+    pub face_backend: FaceBackend,
+    // ONNX models of the local face backend, looked for in ~/.config/glimpse/models when unset
+    pub face_detector_model: Option<String>,
+    pub face_embedding_model: Option<String>,
+}
+
+impl ProviderConfig {
+    pub fn chat_model(&self, default: &str) -> String {
+        self.chat_model.clone().unwrap_or_else(|| default.to_string())
+    }
+
+    // Falls back to the chat model, most providers use one multimodal model for both
+    pub fn vision_model(&self, default: &str) -> String {
+        self.vision_model.clone().unwrap_or_else(|| self.chat_model(default))
+    }
+
+    pub fn embedding_model(&self, default: &str) -> String {
+        self.embedding_model.clone().unwrap_or_else(|| default.to_string())
+    }
+
+    pub fn image_embedding_model(&self, default: &str) -> String {
+        self.image_embedding_model.clone().unwrap_or_else(|| default.to_string())
+    }
+}
+
+pub async fn get_provider(config: &ProviderConfig) -> Result<Box<dyn Provider>, Box<dyn Error>> {
+    let provider: Box<dyn Provider> = match config.name.as_str() {
+        "bedrock" => Box::new(BedrockProvider::new(config)),
+        "openai" => Box::new(OpenAIProvider::new(config).await),
+        "openai-compatible" => Box::new(OpenAIProvider::compatible(config)),
+        "mock" => Box::new(MockProvider::new(config)),
+        _ => return Err(format!("Unknown provider: {}", config.name).into()),
+    };
+    match config.face_backend {
+        FaceBackend::Provider => Ok(provider),
+        #[cfg(feature = "local-faces")]
+        FaceBackend::Local => Ok(Box::new(LocalFaceProvider::new(provider, config))),
+        #[cfg(not(feature = "local-faces"))]
+        FaceBackend::Local => Err("The local face backend is not built in, rebuild glimpse with --features local-faces".into()),
+    }
+}
@@ -1,12 +1,18 @@
 use aws_config::meta::region::RegionProviderChain;
 
 use aws_sdk_rekognition::config::Region;
-use aws_sdk_rekognition::types::Image;
+use aws_sdk_rekognition::types::{Image, QualityFilter};
 use aws_sdk_rekognition::primitives::Blob;
 use aws_config::BehaviorVersion;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
 use std::error::Error;
 
 use crate::graphics::images;
+use crate::processing::cluster;
+use crate::processing::faces::DiscoveredFace;
+use crate::processing::metadata::{BoundingBox, FaceRegion, FaceSource};
+use crate::processing::util;
 
 async fn rek_client() -> aws_sdk_rekognition::Client {
     let rek_region = std::env::var("AWS_REGION").ok();
@@ -22,38 +28,219 @@ async fn rek_client() -> aws_sdk_rekognition::Client {
     aws_sdk_rekognition::Client::new(&rek_shared_config)
 }
 
-pub async fn compare_faces(reference_file: &str, target_file: &str) -> Result<f32, Box<dyn Error>> {
+// Every face of the target with its similarity to the largest face of the reference. The threshold is
+// left at 0 so callers decide what counts as a match.
+pub async fn compare_faces(reference_file: &str, target_file: &str, max_size: u32) -> Result<Vec<(BoundingBox, f32)>, Box<dyn Error>> {
     let rek_client = rek_client().await;
 
-    let tmp_reference_file = images::resize_temp_image(reference_file, 1000)?; // TODO: make a more scientific decision on the resizes
-    let source_image = Image::builder()
-        .bytes(Blob::new(
-            std::fs::read(&tmp_reference_file)?
-        ))
-        .build();
-    images::clear_temp_file(&tmp_reference_file)?;
+    let resp = rek_client.compare_faces()
+        .source_image(rekognition_image(reference_file, max_size)?)
+        .target_image(rekognition_image(target_file, max_size)?)
+        .similarity_threshold(0.0)
+        .send()
+        .await?;
 
-    let tmp_target_file = images::resize_temp_image(target_file, 1000)?;
-    let target_image = Image::builder()
-        .bytes(Blob::new(
-            std::fs::read(&tmp_target_file)?
-        ))
-        .build();
-    images::clear_temp_file(&tmp_target_file)?;
+    Ok(resp
+        .face_matches()
+        .iter()
+        .filter_map(|face_match| {
+            let face = face_match.face()?;
+            Some((bounding_box(face.bounding_box()?), face_match.similarity().unwrap_or_default()))
+        })
+        .collect())
+}
 
+// Faces in an image, left to right so their order is stable for --face-index
+pub async fn detect_faces(image_file: &str, max_size: u32) -> Result<Vec<BoundingBox>, Box<dyn Error>> {
+    let rek_client = rek_client().await;
 
-    let resp = rek_client.compare_faces()
-        .source_image(source_image)
-        .target_image(target_image)
+    let resp = rek_client.detect_faces()
+        .image(rekognition_image(image_file, max_size)?)
         .send()
         .await?;
 
-    // Grab first match if available
-    if resp.face_matches.as_ref().map(|v| v.len()).unwrap_or(0) > 0 {
-        let face_match = resp.face_matches.as_ref().unwrap().first().unwrap();
-        return Ok(face_match.similarity.unwrap());
+    let mut faces: Vec<BoundingBox> = resp
+        .face_details()
+        .iter()
+        .filter_map(|face| face.bounding_box().map(bounding_box))
+        .collect();
+    faces.sort_by(|a, b| a.left.total_cmp(&b.left));
+    Ok(faces)
+}
+
+fn bounding_box(bounding_box: &aws_sdk_rekognition::types::BoundingBox) -> BoundingBox {
+    BoundingBox {
+        left: bounding_box.left().unwrap_or_default(),
+        top: bounding_box.top().unwrap_or_default(),
+        width: bounding_box.width().unwrap_or(1.0),
+        height: bounding_box.height().unwrap_or(1.0),
+    }
+}
+
+// Rekognition image of a file, resized to keep the request small
+fn rekognition_image(file: &str, max_size: u32) -> Result<Image, Box<dyn Error>> {
+    let tmp_file = images::resize_temp_image(file, max_size)?;
+    let bytes = std::fs::read(&tmp_file);
+    images::clear_temp_file(&tmp_file)?;
+    Ok(Image::builder().bytes(Blob::new(bytes?)).build())
+}
+
+// Create the collection the first time a person is enrolled
+async fn ensure_collection(rek_client: &aws_sdk_rekognition::Client, collection: &str) -> Result<(), Box<dyn Error>> {
+    match rek_client.create_collection().collection_id(collection).send().await {
+        Ok(_) => {
+            println!("Created face collection {}", collection);
+            Ok(())
+        }
+        Err(e) => {
+            let e = e.into_service_error();
+            if e.is_resource_already_exists_exception() {
+                Ok(())
+            } else {
+                Err(e.into())
+            }
+        }
     }
+}
+
+// Index the largest face of a reference image under the person, returns the number of faces indexed
+pub async fn enroll_face(collection: &str, person_name: &str, image_file: &str, max_size: u32) -> Result<usize, Box<dyn Error>> {
+    let rek_client = rek_client().await;
+    ensure_collection(&rek_client, collection).await?;
+
+    let resp = rek_client.index_faces()
+        .collection_id(collection)
+        .image(rekognition_image(image_file, max_size)?)
+        .external_image_id(util::encode_name(person_name))
+        .max_faces(1)
+        .quality_filter(QualityFilter::Auto)
+        .send()
+        .await?;
+    Ok(resp.face_records().len())
+}
 
-    // No face match
-    Ok(0.0)
-}
\ No newline at end of file
+// Every enrolled person found in an image, at their best matching face. SearchFacesByImage only
+// looks at the largest face, so each detected face is searched on its own.
+pub async fn search_faces(collection: &str, image_file: &str, threshold: f32, max_size: u32) -> Result<Vec<FaceRegion>, Box<dyn Error>> {
+    let rek_client = rek_client().await;
+
+    let mut people: Vec<FaceRegion> = vec![];
+    for bounding_box in detect_faces(image_file, max_size).await? {
+        let tmp_face_file = images::crop_face_temp_image(image_file, &bounding_box)?;
+        let face_image = rekognition_image(&tmp_face_file, max_size);
+        images::clear_temp_file(&tmp_face_file)?;
+
+        let resp = rek_client.search_faces_by_image()
+            .collection_id(collection)
+            .image(face_image?)
+            .face_match_threshold(threshold)
+            .max_faces(1)
+            .send()
+            .await;
+        let resp = match resp {
+            Ok(resp) => resp,
+            // No face found again in the crop, nothing to match
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_invalid_parameter_exception()) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        for face_match in resp.face_matches() {
+            let person_name = match face_match.face().and_then(|face| face.external_image_id()) {
+                Some(external_image_id) => util::decode_name(external_image_id),
+                None => continue,
+            };
+            let similarity = face_match.similarity().unwrap_or_default();
+            match people.iter_mut().find(|region| region.person == person_name) {
+                Some(region) if region.similarity >= similarity => {}
+                Some(region) => *region = FaceRegion::new(&person_name, bounding_box, similarity, FaceSource::Rekognition, collection),
+                None => people.push(FaceRegion::new(&person_name, bounding_box, similarity, FaceSource::Rekognition, collection)),
+            }
+        }
+    }
+    Ok(people)
+}
+
+// Scratch collections discover-faces indexes every face into, one per run
+const DISCOVER_COLLECTION_PREFIX: &str = "glimpse-discover";
+// Most faces indexed per image, and matches returned per face
+const MAX_FACES_PER_IMAGE: i32 = 100;
+const MAX_FACE_MATCHES: i32 = 4096;
+
+// A random collection id, so runs at the same time never share or delete each other's collection
+fn discover_collection() -> String {
+    format!("{}-{:016x}", DISCOVER_COLLECTION_PREFIX, RandomState::new().build_hasher().finish())
+}
+
+// Index the faces of every image into the scratch collection, then link each face to the faces it matches
+async fn match_faces(rek_client: &aws_sdk_rekognition::Client, collection: &str, image_files: &[String], threshold: f32, max_size: u32) -> Result<(Vec<DiscoveredFace>, Vec<(usize, usize)>), Box<dyn Error>> {
+    let mut face_ids: Vec<String> = vec![];
+    let mut faces: Vec<DiscoveredFace> = vec![];
+    for (i, file) in image_files.iter().enumerate() {
+        println!("{} / {}: {}", i + 1, image_files.len(), file);
+        let image = match rekognition_image(file, max_size) {
+            Ok(image) => image,
+            Err(e) => {
+                println!("Failed to read {}: {:?}", file, e);
+                continue;
+            }
+        };
+        let resp = rek_client.index_faces()
+            .collection_id(collection)
+            .image(image)
+            .max_faces(MAX_FACES_PER_IMAGE)
+            .quality_filter(QualityFilter::Auto)
+            .send()
+            .await;
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                println!("Failed to index faces of {}: {:?}", file, e);
+                continue;
+            }
+        };
+        for face in resp.face_records().iter().filter_map(|record| record.face()) {
+            if let (Some(face_id), Some(face_box)) = (face.face_id(), face.bounding_box()) {
+                face_ids.push(face_id.to_string());
+                faces.push(DiscoveredFace { file: file.clone(), bounding_box: bounding_box(face_box) });
+            }
+        }
+    }
+
+    let face_indexes: HashMap<&str, usize> = face_ids.iter().enumerate().map(|(i, id)| (id.as_str(), i)).collect();
+    let mut links = vec![];
+    for (i, face_id) in face_ids.iter().enumerate() {
+        let resp = rek_client.search_faces()
+            .collection_id(collection)
+            .face_id(face_id)
+            .face_match_threshold(threshold)
+            .max_faces(MAX_FACE_MATCHES)
+            .send()
+            .await?;
+        for matched_id in resp.face_matches().iter().filter_map(|face_match| face_match.face()?.face_id()) {
+            if let Some(j) = face_indexes.get(matched_id) {
+                links.push((i, *j));
+            }
+        }
+    }
+    Ok((faces, links))
+}
+
+// Rekognition doesn't expose face embeddings, so every face is indexed into a scratch collection and
+// searched against all the others, faces are grouped through the matches
+pub async fn discover_faces(image_files: &[String], threshold: f32, max_size: u32) -> Result<Vec<Vec<DiscoveredFace>>, Box<dyn Error>> {
+    let rek_client = rek_client().await;
+    let collection = discover_collection();
+    ensure_collection(&rek_client, &collection).await?;
+
+    // The collection holds faces of every image, it is deleted whether matching worked or not
+    let matched = match_faces(&rek_client, &collection, image_files, threshold, max_size).await;
+    let deleted = rek_client.delete_collection().collection_id(&collection).send().await;
+    let (faces, links) = matched?;
+    deleted.map_err(|e| format!("Failed to delete face collection {}, delete it by hand: {:?}", collection, e))?;
+
+    let mut faces: Vec<Option<DiscoveredFace>> = faces.into_iter().map(Some).collect();
+    Ok(cluster::connected_groups(faces.len(), links)
+        .into_iter()
+        .map(|group| group.into_iter().filter_map(|i| faces[i].take()).collect())
+        .collect())
+}
@@ -1,5 +1,6 @@
-use image::{imageops::FilterType, GenericImageView};
+use image::{imageops::{self, FilterType}, DynamicImage, GenericImageView, RgbImage};
 use anyhow::Result;
+use clap::ValueEnum;
 use std::path::PathBuf;
 use std::path::Path;
 
@@ -8,6 +9,14 @@ use aws_sdk_bedrockruntime::{
     primitives::Blob,
 };
 
+use crate::processing::metadata::BoundingBox;
+
+// Share of a face's size added on every side when cropping it
+const FACE_CROP_MARGIN: f32 = 0.25;
+// Side in pixels of each face on a contact sheet
+const CONTACT_SHEET_TILE: u32 = 160;
+const MAX_CONTACT_SHEET_FACES: usize = 64;
+
 pub fn resize_image(input_path: &str, output_path: &str, max_size: u32) -> Result<()> {
     // Open the image
     let img = image::open(&input_path)?;
@@ -50,6 +59,58 @@ pub fn resize_temp_image(input_path: &str, max_size: u32) -> Result<String> {
     Ok(output_path)
 }
 
+// Region given as fractions of the width and height, as face detection reports them, grown by
+// margin times its size on every side and clamped to the image
+fn crop_region(img: &DynamicImage, left: f32, top: f32, width: f32, height: f32, margin: f32) -> DynamicImage {
+    let (image_width, image_height) = img.dimensions();
+    let x0 = ((left - width * margin).max(0.0) * image_width as f32) as u32;
+    let y0 = ((top - height * margin).max(0.0) * image_height as f32) as u32;
+    let x1 = (((left + width * (1.0 + margin)).min(1.0) * image_width as f32) as u32).max(x0 + 1);
+    let y1 = (((top + height * (1.0 + margin)).min(1.0) * image_height as f32) as u32).max(y0 + 1);
+    img.crop_imm(x0, y0, x1 - x0, y1 - y0)
+}
+
+pub fn crop_temp_image(input_path: &str, left: f32, top: f32, width: f32, height: f32, margin: f32) -> Result<String> {
+    let img = image::open(input_path)?;
+
+    let temp_file = tempfile::Builder::new()
+        .prefix("gimpse_cropped_")
+        .suffix(".jpg")
+        .keep(true)
+        .tempfile()?;
+    let output_path = temp_file.path().to_str().unwrap().to_string();
+
+    crop_region(&img, left, top, width, height, margin).to_rgb8().save(&output_path)?;
+    Ok(output_path)
+}
+
+// A face with some of its surroundings, face recognition needs those to detect the face again in the crop
+pub fn crop_face_temp_image(input_path: &str, face: &BoundingBox) -> Result<String> {
+    crop_temp_image(input_path, face.left, face.top, face.width, face.height, FACE_CROP_MARGIN)
+}
+
+// Grid of face crops, at most MAX_CONTACT_SHEET_FACES of them, row by row
+pub fn contact_sheet(faces: &[(String, BoundingBox)], output_path: &str) -> Result<()> {
+    let faces = &faces[..faces.len().min(MAX_CONTACT_SHEET_FACES)];
+    let columns = (faces.len() as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (faces.len() as u32).div_ceil(columns).max(1);
+    let mut sheet = RgbImage::new(columns * CONTACT_SHEET_TILE, rows * CONTACT_SHEET_TILE);
+
+    for (i, (file, face)) in faces.iter().enumerate() {
+        let img = match image::open(file) {
+            Ok(img) => img,
+            Err(_) => continue,
+        };
+        let tile = crop_region(&img, face.left, face.top, face.width, face.height, FACE_CROP_MARGIN)
+            .resize_to_fill(CONTACT_SHEET_TILE, CONTACT_SHEET_TILE, FilterType::Triangle)
+            .to_rgb8();
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        imageops::replace(&mut sheet, &tile, (column * CONTACT_SHEET_TILE) as i64, (row * CONTACT_SHEET_TILE) as i64);
+    }
+    sheet.save(output_path)?;
+    Ok(())
+}
+
 pub fn clear_temp_file(file_path: &str) -> Result<()> {
     let path = PathBuf::from(file_path);
     std::fs::remove_file(path)?;
@@ -76,4 +137,139 @@ pub fn path_to_bedrock_image_block(file_path: &str) -> Result<ImageBlock> {
         .source(ImageSource::Bytes(Blob::new(bytes)))
         .build()?;
     Ok(image)
-}
\ No newline at end of file
+}
+
+// 64 bit average hash, each bit is set when the pixel of an 8x8 grayscale thumbnail is above the mean
+pub fn average_hash(file_path: &str) -> Result<u64> {
+    let img = image::open(file_path)?
+        .resize_exact(8, 8, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<u32> = img.pixels().map(|p| p.0[0] as u32).collect();
+    let mean = pixels.iter().sum::<u32>() / pixels.len() as u32;
+
+    let mut hash = 0u64;
+    for (i, pixel) in pixels.iter().enumerate() {
+        if *pixel > mean {
+            hash |= 1 << i;
+        }
+    }
+    Ok(hash)
+}
+
+// 64 bit difference hash, each bit is set when a pixel of a 9x8 grayscale thumbnail is brighter
+// than its right neighbour. Robust to exposure and contrast changes.
+pub fn difference_hash(file_path: &str) -> Result<u64> {
+    let img = image::open(file_path)?
+        .resize_exact(9, 8, FilterType::Triangle)
+        .to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            if img.get_pixel(x, y).0[0] > img.get_pixel(x + 1, y).0[0] {
+                hash |= 1 << (y * 8 + x);
+            }
+        }
+    }
+    Ok(hash)
+}
+
+// 64 bit perceptual hash, each bit is set when one of the 8x8 lowest frequencies of the DCT of a
+// 32x32 grayscale thumbnail is above their median. The slowest, but survives resizing, recompression
+// and small edits best.
+pub fn perceptual_hash(file_path: &str) -> Result<u64> {
+    const SIZE: usize = 32;
+    let img = image::open(file_path)?
+        .resize_exact(SIZE as u32, SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<f64> = img.pixels().map(|p| p.0[0] as f64).collect();
+
+    // Only the 8x8 lowest frequencies of the 2D DCT-II are needed
+    let cosines: Vec<Vec<f64>> = (0..8)
+        .map(|u| (0..SIZE).map(|x| ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * SIZE) as f64).cos()).collect())
+        .collect();
+    let mut frequencies = vec![];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    sum += pixels[y * SIZE + x] * cosines[u][x] * cosines[v][y];
+                }
+            }
+            frequencies.push(sum);
+        }
+    }
+
+    // The DC term is the average brightness, it is left out of the median
+    let mut sorted = frequencies[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+    let mut hash = 0u64;
+    for (i, frequency) in frequencies.iter().enumerate() {
+        if *frequency > median {
+            hash |= 1 << i;
+        }
+    }
+    Ok(hash)
+}
+
+// Perceptual hashes, similar images have hashes a few bits apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ImageHash {
+    /// Brightness above the mean, fastest
+    Average,
+    /// Brightness gradients
+    Difference,
+    /// Low DCT frequencies, most robust
+    #[default]
+    Perceptual,
+}
+
+pub fn image_hash(file_path: &str, kind: ImageHash) -> Result<u64> {
+    match kind {
+        ImageHash::Average => average_hash(file_path),
+        ImageHash::Difference => difference_hash(file_path),
+        ImageHash::Perceptual => perceptual_hash(file_path),
+    }
+}
+
+// Variance of the Laplacian of the grayscale image, higher is sharper. Computed on a copy scaled to
+// 512 pixels so images of different resolutions are comparable.
+pub fn sharpness(file_path: &str) -> Result<f64> {
+    let img = image::open(file_path)?
+        .resize(512, 512, FilterType::Triangle)
+        .to_luma8();
+    let (width, height) = img.dimensions();
+    if width < 3 || height < 3 {
+        return Ok(0.0);
+    }
+
+    let mut laplacians = vec![];
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let pixel = |x: u32, y: u32| img.get_pixel(x, y).0[0] as f64;
+            laplacians.push(pixel(x - 1, y) + pixel(x + 1, y) + pixel(x, y - 1) + pixel(x, y + 1) - 4.0 * pixel(x, y));
+        }
+    }
+    let mean = laplacians.iter().sum::<f64>() / laplacians.len() as f64;
+    Ok(laplacians.iter().map(|l| (l - mean) * (l - mean)).sum::<f64>() / laplacians.len() as f64)
+}
+
+// Width and height from the image header, without decoding it
+pub fn dimensions(file_path: &str) -> Result<(u32, u32)> {
+    Ok(image::image_dimensions(file_path)?)
+}
+
+// Mean (r, g, b) over the whole image
+pub fn average_color(file_path: &str) -> Result<(u8, u8, u8)> {
+    let img = image::open(file_path)?.to_rgb8();
+    let count = (img.width() as u64 * img.height() as u64).max(1);
+    let (mut r, mut g, mut b) = (0u64, 0u64, 0u64);
+    for pixel in img.pixels() {
+        r += pixel.0[0] as u64;
+        g += pixel.0[1] as u64;
+        b += pixel.0[2] as u64;
+    }
+    Ok(((r / count) as u8, (g / count) as u8, (b / count) as u8))
+}
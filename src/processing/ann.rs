@@ -0,0 +1,291 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::error::Error;
+
+use crate::processing::util::fnv1a;
+
+// Links kept per node on the upper layers, layer 0 keeps twice as many
+const M: usize = 16;
+const EF_CONSTRUCTION: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: u32,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A node of the graph, its normalized vector and its links on each layer it is on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub vector: Vec<f32>,
+    // links[layer], the node is on layers 0 to links.len() - 1
+    pub links: Vec<Vec<u32>>,
+}
+
+// Where a search reads nodes from, the graph being built in memory or a stored one that only loads
+// the nodes a search reaches
+pub trait Graph {
+    fn dimension(&self) -> usize;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn entry_point(&self) -> Option<u32>;
+
+    fn with_node<T>(&self, node: u32, f: impl FnOnce(&Node) -> T) -> Result<T, Box<dyn Error>>;
+}
+
+// Hierarchical navigable small world graph over normalized vectors, distance is 1 - cosine similarity.
+// Vectors are kept as f32 to halve the size, callers re-rank the candidates with the original vectors.
+pub struct Hnsw {
+    dimension: usize,
+    nodes: Vec<Node>,
+    entry_point: Option<u32>,
+}
+
+fn normalize(vector: &[f64]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm == 0.0 {
+        return vector.iter().map(|x| *x as f32).collect();
+    }
+    vector.iter().map(|x| (x / norm) as f32).collect()
+}
+
+fn distance(query: &[f32], vector: &[f32]) -> f32 {
+    1.0 - query.iter().zip(vector).map(|(a, b)| a * b).sum::<f32>()
+}
+
+// Layer a node goes up to, exponentially rarer for higher layers. Derived from the node rather than
+// random, so the same library always builds the same graph.
+fn node_level(node: u32) -> usize {
+    let uniform = ((fnv1a(&node.to_le_bytes()) >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    (-uniform.ln() / (M as f64).ln()) as usize
+}
+
+fn max_links(layer: usize) -> usize {
+    if layer == 0 { 2 * M } else { M }
+}
+
+fn top_layer<G: Graph>(graph: &G, entry_point: u32) -> Result<usize, Box<dyn Error>> {
+    graph.with_node(entry_point, |node| node.links.len().saturating_sub(1))
+}
+
+fn entry_candidate<G: Graph>(graph: &G, query: &[f32], entry_point: u32) -> Result<Candidate, Box<dyn Error>> {
+    let distance = graph.with_node(entry_point, |node| distance(query, &node.vector))?;
+    Ok(Candidate { distance, node: entry_point })
+}
+
+// Up to ef nodes of a layer closest to the query, nearest first
+fn search_layer<G: Graph>(graph: &G, query: &[f32], entry_points: &[Candidate], ef: usize, layer: usize) -> Result<Vec<Candidate>, Box<dyn Error>> {
+    let mut visited: HashSet<u32> = entry_points.iter().map(|candidate| candidate.node).collect();
+    // Nodes still to expand, closest first, and the best found so far, furthest first
+    let mut candidates: BinaryHeap<Reverse<Candidate>> = entry_points.iter().copied().map(Reverse).collect();
+    let mut nearest: BinaryHeap<Candidate> = entry_points.iter().copied().collect();
+    while nearest.len() > ef {
+        nearest.pop();
+    }
+
+    while let Some(Reverse(candidate)) = candidates.pop() {
+        let furthest = nearest.peek().map(|c| c.distance).unwrap_or(f32::MAX);
+        if nearest.len() >= ef && candidate.distance > furthest {
+            break;
+        }
+        let links = graph.with_node(candidate.node, |node| node.links.get(layer).cloned().unwrap_or_default())?;
+        for neighbour in links {
+            if !visited.insert(neighbour) {
+                continue;
+            }
+            let distance = graph.with_node(neighbour, |node| distance(query, &node.vector))?;
+            let furthest = nearest.peek().map(|c| c.distance).unwrap_or(f32::MAX);
+            if nearest.len() < ef || distance < furthest {
+                let neighbour = Candidate { distance, node: neighbour };
+                candidates.push(Reverse(neighbour));
+                nearest.push(neighbour);
+                if nearest.len() > ef {
+                    nearest.pop();
+                }
+            }
+        }
+    }
+    Ok(nearest.into_sorted_vec())
+}
+
+// Approximate k nearest nodes and their distance, nearest first. A larger ef is slower but misses
+// fewer of the true nearest neighbours.
+pub fn search<G: Graph>(graph: &G, query: &[f64], k: usize, ef: usize) -> Result<Vec<(usize, f32)>, Box<dyn Error>> {
+    let entry_point = match graph.entry_point() {
+        Some(entry_point) if query.len() == graph.dimension() => entry_point,
+        _ => return Ok(vec![]),
+    };
+    let query = normalize(query);
+
+    let mut entry_points = vec![entry_candidate(graph, &query, entry_point)?];
+    for layer in (1..=top_layer(graph, entry_point)?).rev() {
+        entry_points = search_layer(graph, &query, &entry_points, 1, layer)?;
+    }
+    Ok(search_layer(graph, &query, &entry_points, ef.max(k), 0)?
+        .into_iter()
+        .take(k)
+        .map(|candidate| (candidate.node as usize, candidate.distance))
+        .collect())
+}
+
+impl Graph for Hnsw {
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn entry_point(&self) -> Option<u32> {
+        self.entry_point
+    }
+
+    fn with_node<T>(&self, node: u32, f: impl FnOnce(&Node) -> T) -> Result<T, Box<dyn Error>> {
+        match self.nodes.get(node as usize) {
+            Some(node) => Ok(f(node)),
+            None => Err(format!("No node {} in the vector index", node).into()),
+        }
+    }
+}
+
+impl Hnsw {
+    pub fn new(dimension: usize) -> Hnsw {
+        Hnsw { dimension, nodes: vec![], entry_point: None }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    // Keep only the closest links of a node that has too many
+    fn prune(&mut self, node: u32, layer: usize) {
+        let current = &self.nodes[node as usize];
+        if current.links[layer].len() <= max_links(layer) {
+            return;
+        }
+        let mut links: Vec<Candidate> = current.links[layer]
+            .iter()
+            .map(|&link| Candidate { distance: distance(&current.vector, &self.nodes[link as usize].vector), node: link })
+            .collect();
+        links.sort();
+        links.truncate(max_links(layer));
+        self.nodes[node as usize].links[layer] = links.into_iter().map(|link| link.node).collect();
+    }
+
+    // Add a vector, its node is the number of vectors inserted before it
+    pub fn insert(&mut self, vector: &[f64]) -> Result<(), Box<dyn Error>> {
+        if vector.len() != self.dimension {
+            return Err(format!("Vector has {} dimensions, the index has {}", vector.len(), self.dimension).into());
+        }
+        let node = self.nodes.len() as u32;
+        let query = normalize(vector);
+        let level = node_level(node);
+        self.nodes.push(Node { vector: query.clone(), links: vec![vec![]; level + 1] });
+
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => {
+                self.entry_point = Some(node);
+                return Ok(());
+            }
+        };
+        let top_layer = top_layer(self, entry_point)?;
+
+        // Greedy descent to the node's top layer, then link it on every layer below
+        let mut entry_points = vec![entry_candidate(self, &query, entry_point)?];
+        for layer in (level + 1..=top_layer).rev() {
+            entry_points = search_layer(self, &query, &entry_points, 1, layer)?;
+        }
+        for layer in (0..=level.min(top_layer)).rev() {
+            let nearest = search_layer(self, &query, &entry_points, EF_CONSTRUCTION, layer)?;
+            let neighbours: Vec<u32> = nearest.iter().take(M).map(|candidate| candidate.node).collect();
+            for &neighbour in &neighbours {
+                self.nodes[neighbour as usize].links[layer].push(node);
+                self.prune(neighbour, layer);
+            }
+            self.nodes[node as usize].links[layer] = neighbours;
+            entry_points = nearest;
+        }
+
+        if level > top_layer {
+            self.entry_point = Some(node);
+        }
+        Ok(())
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: &mut usize) -> Result<u32, Box<dyn Error>> {
+    let value = bytes
+        .get(*offset..*offset + 4)
+        .ok_or("Corrupt vector index, node links are truncated")?;
+    *offset += 4;
+    Ok(u32::from_le_bytes(value.try_into()?))
+}
+
+// A node is stored as its vector, f32 values, and its links, for each layer the number of links
+// followed by the links
+pub fn vector_to_bytes(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+pub fn links_to_bytes(links: &[Vec<u32>]) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend((links.len() as u32).to_le_bytes());
+    for layer in links {
+        bytes.extend((layer.len() as u32).to_le_bytes());
+        for link in layer {
+            bytes.extend(link.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+// Checked against the index the node belongs to, so a corrupt row is an error rather than a panic
+// or an out of bounds link
+pub fn node_from_bytes(vector: &[u8], links: &[u8], dimension: usize, count: usize) -> Result<Node, Box<dyn Error>> {
+    if vector.len() != dimension * 4 {
+        return Err(format!("Corrupt vector index, node vector is {} bytes, expected {}", vector.len(), dimension * 4).into());
+    }
+    let vector = vector
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+
+    let mut offset = 0;
+    let mut layers = vec![];
+    for _ in 0..read_u32(links, &mut offset)? {
+        let mut layer = vec![];
+        for _ in 0..read_u32(links, &mut offset)? {
+            let link = read_u32(links, &mut offset)?;
+            if link as usize >= count {
+                return Err(format!("Corrupt vector index, link to node {} of {}", link, count).into());
+            }
+            layer.push(link);
+        }
+        layers.push(layer);
+    }
+    if layers.is_empty() || offset != links.len() {
+        return Err("Corrupt vector index, malformed node links".into());
+    }
+    Ok(Node { vector, links: layers })
+}
@@ -1,32 +1,360 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::ai::provider::FaceBackend;
+use crate::graphics::images::ImageHash;
+use crate::processing::metadata::{EmbeddingEncoding, EmbeddingSpace, Storage};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    // Core arguments
-    #[arg(short, long, required = true)]
-    pub action: String,
-    #[clap(short, long, required = true)]
-    pub files: String,
-    #[clap(short, long, default_value = "10")]
-    pub top: u32,
-    #[clap(short, long, default_value = "bedrock")]
-    pub provider: String,
-    // Tagging arguments
-    #[clap(short, long, required_if_eq("action", "tag-person"), default_value = "")]
-    pub person_name: String,
-    #[clap(short, long, required_if_eq("action", "tag-person"), default_value = "")]
-    pub reference_file: String,
-    #[clap(short, long, default_value = "85.0")]
-    pub confidence: f32,
-    #[clap(short, long, action)]
-    pub overwrite: bool,
-    #[clap(short, long, default_value = "")]
-    pub description: String,
-    #[clap(short, long, required_if_eq("action", "tag"), default_value = "")]
-    pub tags: String,
-    #[clap(short, long, required_if_eq("action", "sort-by-tag"), default_value = "")]
-    pub output_directory: String,
-    #[arg(short, long, required = false, default_value = "")]
-    pub prompt: String
-}
\ No newline at end of file
+    #[command(subcommand)]
+    pub action: Action,
+    #[command(flatten)]
+    pub provider: ProviderArgs,
+    /// Where metadata is read from and written to [default: exif]
+    #[arg(short, long, global = true, value_enum)]
+    pub storage: Option<Storage>,
+    /// Also write tags, people and description to standard XMP fields read by other photo tools
+    #[arg(long, global = true)]
+    pub standard_fields: bool,
+    /// How embedding vectors are written, smaller encodings keep large vectors within EXIF limits [default: f32]
+    #[arg(long, global = true, value_enum)]
+    pub embedding_encoding: Option<EmbeddingEncoding>,
+}
+
+// Provider selection, shared by every action
+#[derive(clap::Args)]
+pub struct ProviderArgs {
+    /// AI provider: bedrock, openai, openai-compatible or mock [default: bedrock]
+    #[arg(short, long, global = true, env = "GLIMPSE_PROVIDER")]
+    pub provider: Option<String>,
+    // Model arguments, providers use their own defaults when unset
+    /// Model used for text completion
+    #[arg(long, global = true, env = "GLIMPSE_CHAT_MODEL")]
+    pub chat_model: Option<String>,
+    /// Model used to describe images, defaults to the chat model
+    #[arg(long, global = true, env = "GLIMPSE_VISION_MODEL")]
+    pub vision_model: Option<String>,
+    /// Model used to generate embeddings
+    #[arg(long, global = true, env = "GLIMPSE_EMBEDDING_MODEL")]
+    pub embedding_model: Option<String>,
+    /// Multimodal model used to embed images and the queries searching them
+    #[arg(long, global = true, env = "GLIMPSE_IMAGE_EMBEDDING_MODEL")]
+    pub image_embedding_model: Option<String>,
+    // OpenAI compatible provider arguments
+    /// Base url of the openai-compatible server [default: http://localhost:11434/v1]
+    #[arg(long, global = true, env = "GLIMPSE_BASE_URL")]
+    pub base_url: Option<String>,
+    /// API key sent to the openai-compatible server
+    #[arg(long, global = true, env = "GLIMPSE_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// Longest side, in pixels, of images sent to the provider [default: 1000]
+    #[arg(long, global = true)]
+    pub max_image_size: Option<u32>,
+    // Local face backend arguments
+    /// What detects and recognizes faces, local runs ONNX models on the CPU [default: provider]
+    #[arg(long, global = true, value_enum, env = "GLIMPSE_FACE_BACKEND")]
+    pub face_backend: Option<FaceBackend>,
+    /// UltraFace ONNX face detector [default: ~/.config/glimpse/models/version-RFB-320.onnx]
+    #[arg(long, global = true, env = "GLIMPSE_FACE_DETECTOR_MODEL")]
+    pub face_detector_model: Option<String>,
+    /// ArcFace ONNX face embedding model [default: ~/.config/glimpse/models/w600k_mbf.onnx]
+    #[arg(long, global = true, env = "GLIMPSE_FACE_EMBEDDING_MODEL")]
+    pub face_embedding_model: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Action {
+    /// Tag a known person in every image where their face matches the reference image
+    TagPerson {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Name to tag the person with
+        #[arg(short = 'n', long)]
+        person_name: String,
+        /// Image containing the person's face
+        #[arg(short, long)]
+        reference_file: String,
+        /// Which face of the reference to use when it has several, counting from 0 left to right
+        #[arg(long)]
+        face_index: Option<usize>,
+        /// Minimum face similarity (0-100) needed to tag [default: 85.0]
+        #[arg(short, long)]
+        confidence: Option<f32>,
+    },
+    /// Remove a wrongly tagged person from images, and never tag them in those images again
+    UntagPerson {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Name of the person to remove
+        #[arg(short = 'n', long)]
+        person_name: String,
+    },
+    /// Add reference images of a person to the face collection used by tag-people
+    EnrollPerson {
+        /// Glob of reference images, each with the person as its largest face
+        #[arg(short, long)]
+        files: String,
+        /// Name to tag the person with
+        #[arg(short = 'n', long)]
+        person_name: String,
+        /// Face collection to enroll into [default: glimpse]
+        #[arg(long)]
+        collection: Option<String>,
+    },
+    /// Tag every enrolled person found in images, in a single pass
+    TagPeople {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Minimum face similarity (0-100) needed to tag [default: 85.0]
+        #[arg(short, long)]
+        confidence: Option<f32>,
+        /// Face collection people were enrolled into [default: glimpse]
+        #[arg(long)]
+        collection: Option<String>,
+    },
+    /// Find every face in images and group them by person, with a contact sheet of each group to name
+    DiscoverFaces {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Directory the contact sheets and face-clusters.json are written to
+        #[arg(short, long)]
+        output_directory: Option<String>,
+        /// Minimum face similarity (0-100) for two faces to be the same person [default: 85.0]
+        #[arg(short, long)]
+        confidence: Option<f32>,
+        /// Smallest number of faces a group needs to be kept
+        #[arg(long, default_value_t = 2)]
+        min_faces: usize,
+    },
+    /// Tag a person in every image of a face group found by discover-faces
+    NameCluster {
+        /// Directory discover-faces wrote the groups to
+        #[arg(short, long)]
+        output_directory: Option<String>,
+        /// Number of the group, as on its contact sheet
+        #[arg(long)]
+        cluster: usize,
+        /// Name to tag the person with
+        #[arg(short = 'n', long, visible_alias = "person")]
+        person_name: String,
+    },
+    /// List images tagged with a person
+    FindPerson {
+        /// Glob of images to search
+        #[arg(short, long)]
+        files: String,
+        /// Name of the person to find
+        #[arg(short = 'n', long)]
+        person_name: String,
+    },
+    /// Describe images with the LLM and store the description and its embedding
+    TagDescription {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Replace existing descriptions
+        #[arg(short, long)]
+        overwrite: bool,
+        /// Prompt used instead of the default description prompt
+        #[arg(long, conflicts_with = "prompt_name")]
+        prompt: Option<String>,
+        /// Name of a prompt defined in the config [prompts] table
+        #[arg(long)]
+        prompt_name: Option<String>,
+    },
+    /// Classify images into one of the given tags based on their description
+    Tag {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Comma separated list of tags to choose from
+        #[arg(short, long, required = true, value_delimiter = ',')]
+        tags: Vec<String>,
+        /// Replace existing tags
+        #[arg(short, long)]
+        overwrite: bool,
+    },
+    /// Remove wrong tags from images, and never tag those images with them again
+    Untag {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Comma separated list of tags to remove
+        #[arg(short, long, required = true, value_delimiter = ',')]
+        tags: Vec<String>,
+    },
+    /// Remove all glimpse metadata from images, restoring any caption they had before
+    ClearMetadata {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+    },
+    /// Move images into a directory named after their first tag
+    SortByTag {
+        /// Glob of images to sort
+        #[arg(short, long)]
+        files: String,
+        /// Directory the tag directories are created in
+        #[arg(short, long)]
+        output_directory: Option<String>,
+    },
+    /// List the images most similar to a reference image with a description
+    FindSimilar {
+        /// Glob of images to search
+        #[arg(short, long)]
+        files: String,
+        /// Image to compare against
+        #[arg(short, long)]
+        reference_file: String,
+        /// Number of results [default: 10]
+        #[arg(short, long)]
+        top: Option<u32>,
+        /// Embeddings to compare
+        #[arg(long, value_enum, default_value_t)]
+        space: EmbeddingSpace,
+        /// Also search images embedded with other models, by embedding the reference with each of them
+        #[arg(long)]
+        all_models: bool,
+    },
+    /// List the images best matching a query, e.g. `person:Alice tag:beach -tag:work "sunset over water"`
+    Find {
+        /// Glob of images to search
+        #[arg(short, long)]
+        files: String,
+        /// Text to search for, with optional person:NAME and tag:TAG filters, prefix a filter with - to exclude
+        #[arg(short, long, visible_alias = "description", short_alias = 'd')]
+        query: String,
+        /// Number of results [default: 10]
+        #[arg(short, long)]
+        top: Option<u32>,
+        /// Weight (0-1) of keyword matches against semantic similarity in the ranking [default: 0.3]
+        #[arg(long)]
+        keyword_weight: Option<f64>,
+        /// Embeddings to match the query text against, image matches it against the images themselves
+        #[arg(long, value_enum, default_value_t)]
+        space: EmbeddingSpace,
+        /// Also search images embedded with other models, by embedding the query with each of them
+        #[arg(long)]
+        all_models: bool,
+    },
+    /// Embed the images themselves with a multimodal model, for find-similar and find with --space image
+    EmbedImages {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Replace existing image embeddings
+        #[arg(short, long)]
+        overwrite: bool,
+    },
+    /// Regenerate description embeddings from the stored descriptions with the embedding model
+    ReEmbed {
+        /// Glob of images to process
+        #[arg(short, long)]
+        files: String,
+        /// Also re-embed images already embedded with the model
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the glimpse metadata of images
+    ShowMetadata {
+        /// Glob of images to show
+        #[arg(short, long)]
+        files: String,
+    },
+    /// Move metadata embedded in the images into xmp sidecar files
+    MigrateToSidecar {
+        /// Glob of images to migrate
+        #[arg(short, long)]
+        files: String,
+        /// Replace existing sidecar files
+        #[arg(short, long)]
+        overwrite: bool,
+    },
+    /// Build or refresh the catalog used by find, find-similar, find-person and show-metadata
+    Index {
+        /// Glob of images to index
+        #[arg(short, long)]
+        files: String,
+        /// Re-read every image instead of only new and changed ones
+        #[arg(long)]
+        rebuild: bool,
+    },
+    /// Group images into automatic albums by their embeddings, and name each album with the LLM
+    Cluster {
+        /// Glob of images to cluster
+        #[arg(short, long)]
+        files: String,
+        /// Number of albums [default: the square root of half the number of images]
+        #[arg(short = 'k', long)]
+        clusters: Option<usize>,
+        /// Embeddings to cluster, from the embedding model in use
+        #[arg(long, value_enum, default_value_t)]
+        space: EmbeddingSpace,
+        /// Also tag images with their album name, so sort-by-tag can sort them into albums
+        #[arg(long)]
+        as_tag: bool,
+        /// Number the albums instead of naming them with the LLM
+        #[arg(long)]
+        no_names: bool,
+    },
+    /// Group near-duplicate images and bursts, and suggest the best one of each group to keep
+    FindDuplicates {
+        /// Glob of images to search
+        #[arg(short, long)]
+        files: String,
+        /// Perceptual hash images are compared with
+        #[arg(long, value_enum, default_value_t)]
+        hash: ImageHash,
+        /// Most hash bits (of 64) two images can differ by and still be duplicates
+        #[arg(long, default_value_t = 8)]
+        threshold: u32,
+        /// Move all but the best image of each group into this directory
+        #[arg(long)]
+        move_rejects: Option<String>,
+        /// Replace the images of each group that are exact copies of the best one with a hard link to it
+        #[arg(long, conflicts_with = "move_rejects")]
+        hard_link: bool,
+    },
+    /// Upgrade metadata written by older versions of glimpse to the current schema
+    Migrate {
+        /// Glob of images to migrate
+        #[arg(short, long)]
+        files: String,
+    },
+}
+
+impl Action {
+    // Files glob the action operates on
+    pub fn files(&self) -> &str {
+        match self {
+            Action::TagPerson { files, .. }
+            | Action::UntagPerson { files, .. }
+            | Action::EnrollPerson { files, .. }
+            | Action::TagPeople { files, .. }
+            | Action::FindPerson { files, .. }
+            | Action::TagDescription { files, .. }
+            | Action::Tag { files, .. }
+            | Action::Untag { files, .. }
+            | Action::ClearMetadata { files }
+            | Action::SortByTag { files, .. }
+            | Action::FindSimilar { files, .. }
+            | Action::Find { files, .. }
+            | Action::EmbedImages { files, .. }
+            | Action::ReEmbed { files, .. }
+            | Action::ShowMetadata { files }
+            | Action::MigrateToSidecar { files, .. }
+            | Action::Index { files, .. }
+            | Action::Cluster { files, .. }
+            | Action::FindDuplicates { files, .. }
+            | Action::DiscoverFaces { files, .. }
+            | Action::Migrate { files } => files,
+            // The groups know their images, the library is found from where they were written
+            Action::NameCluster { output_directory, .. } => output_directory.as_deref().unwrap_or("."),
+        }
+    }
+}
@@ -0,0 +1,565 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::ai::embedding;
+use crate::processing::ann::{self, Graph, Hnsw, Node};
+use crate::processing::metadata::{self, EmbeddingSpace, MetadataStore, PhotoMeta};
+use crate::processing::xmp;
+
+pub const CATALOG_FILE: &str = ".glimpse.db";
+
+// The catalog is only a cache of the metadata, when its layout changes it is dropped and rebuilt
+// by the next index run
+const CATALOG_VERSION: i64 = 5;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS photos (
+    path TEXT PRIMARY KEY,
+    mtime INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    sidecar_mtime INTEGER NOT NULL,
+    storage TEXT NOT NULL,
+    people TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    description TEXT NOT NULL,
+    metadata TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS embeddings (
+    path TEXT NOT NULL,
+    space TEXT NOT NULL,
+    model TEXT NOT NULL,
+    vector BLOB NOT NULL,
+    PRIMARY KEY (path, space, model)
+);
+CREATE TABLE IF NOT EXISTS vector_indexes (
+    space TEXT NOT NULL,
+    model TEXT NOT NULL,
+    storage TEXT NOT NULL,
+    dimension INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    entry_point INTEGER,
+    PRIMARY KEY (space, model, storage)
+);
+CREATE TABLE IF NOT EXISTS vector_index_nodes (
+    space TEXT NOT NULL,
+    model TEXT NOT NULL,
+    storage TEXT NOT NULL,
+    node INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    vector BLOB NOT NULL,
+    links BLOB NOT NULL,
+    PRIMARY KEY (space, model, storage, node)
+);
+";
+
+const DROP_SCHEMA: &str = "
+DROP TABLE IF EXISTS photos;
+DROP TABLE IF EXISTS embeddings;
+DROP TABLE IF EXISTS vector_indexes;
+DROP TABLE IF EXISTS vector_index_nodes;
+";
+
+// Candidates fetched from the vector index per result wanted, before exact re-ranking
+pub const CANDIDATE_FACTOR: usize = 4;
+const MIN_CANDIDATES: usize = 64;
+
+// What the metadata of a file was read from, a row is fresh while this is unchanged
+#[derive(Debug, PartialEq, Eq)]
+struct FileState {
+    mtime: i64,
+    size: i64,
+    // 0 when there is no sidecar
+    sidecar_mtime: i64,
+}
+
+fn mtime(path: &Path) -> Result<i64, Box<dyn Error>> {
+    Ok(std::fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as i64)
+}
+
+fn file_state(file: &str) -> Result<FileState, Box<dyn Error>> {
+    let sidecar = xmp::sidecar_path(file);
+    Ok(FileState {
+        mtime: mtime(Path::new(file))?,
+        size: std::fs::metadata(file)?.len() as i64,
+        sidecar_mtime: if sidecar.is_file() { mtime(&sidecar)? } else { 0 },
+    })
+}
+
+// Rows are keyed by absolute path so globs relative to different directories share them
+fn catalog_key(file: &str) -> String {
+    std::path::absolute(file)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or(file.to_string())
+}
+
+fn embedding_to_blob(embedding: &[f64]) -> Vec<u8> {
+    embedding.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn blob_to_embedding(blob: &[u8]) -> Vec<f64> {
+    blob.chunks_exact(8)
+        .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+        .collect()
+}
+
+// Embedding blobs of a file by space and model
+type Vectors = HashMap<(String, String), Vec<u8>>;
+
+#[derive(Debug, Default)]
+pub struct RefreshStats {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+// A vector index as stored in the catalog. Nodes are read as a search reaches them, so a query only
+// loads the part of the graph it visits.
+struct StoredIndex<'a> {
+    connection: &'a Connection,
+    space: EmbeddingSpace,
+    model: &'a str,
+    storage: &'static str,
+    dimension: usize,
+    count: usize,
+    entry_point: Option<u32>,
+    // Catalog path and node, by node
+    nodes: RefCell<HashMap<u32, (String, Node)>>,
+}
+
+impl StoredIndex<'_> {
+    fn load(&self, node: u32) -> Result<(), Box<dyn Error>> {
+        if self.nodes.borrow().contains_key(&node) {
+            return Ok(());
+        }
+        let (path, vector, links) = self.connection
+            .prepare_cached(
+                "SELECT path, vector, links FROM vector_index_nodes
+                 WHERE space = ?1 AND model = ?2 AND storage = ?3 AND node = ?4",
+            )?
+            .query_row(
+                params![self.space.name(), self.model, self.storage, node],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?)),
+            )
+            .optional()?
+            .ok_or_else(|| format!("Corrupt vector index, node {} is missing", node))?;
+        let loaded = ann::node_from_bytes(&vector, &links, self.dimension, self.count)?;
+        self.nodes.borrow_mut().insert(node, (path, loaded));
+        Ok(())
+    }
+
+    fn path(&self, node: u32) -> Result<String, Box<dyn Error>> {
+        self.load(node)?;
+        Ok(self.nodes.borrow()[&node].0.clone())
+    }
+}
+
+impl Graph for StoredIndex<'_> {
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn entry_point(&self) -> Option<u32> {
+        self.entry_point
+    }
+
+    fn with_node<T>(&self, node: u32, f: impl FnOnce(&Node) -> T) -> Result<T, Box<dyn Error>> {
+        self.load(node)?;
+        Ok(f(&self.nodes.borrow()[&node].1))
+    }
+}
+
+// SQLite catalog of the metadata of a library, so searches don't have to open every image
+pub struct Catalog {
+    connection: Connection,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> Result<Catalog, Box<dyn Error>> {
+        let connection = Connection::open(path)?;
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != CATALOG_VERSION {
+            connection.execute_batch(DROP_SCHEMA)?;
+            connection.pragma_update(None, "user_version", CATALOG_VERSION)?;
+        }
+        connection.execute_batch(SCHEMA)?;
+        Ok(Catalog { connection })
+    }
+
+    // Stored state of every file in the catalog, read in one query rather than one per file
+    fn get_states(&self, store: &MetadataStore) -> Result<HashMap<String, FileState>, Box<dyn Error>> {
+        Ok(self.connection
+            .prepare("SELECT path, mtime, size, sidecar_mtime FROM photos WHERE storage = ?1")?
+            .query_map(params![store.storage.name()], |row| {
+                Ok((row.get::<_, String>(0)?, FileState { mtime: row.get(1)?, size: row.get(2)?, sidecar_mtime: row.get(3)? }))
+            })?
+            .collect::<Result<HashMap<String, FileState>, _>>()?)
+    }
+
+    fn get_vectors(&self, key: &str) -> Result<Vectors, Box<dyn Error>> {
+        Ok(self.connection
+            .prepare("SELECT space, model, vector FROM embeddings WHERE path = ?1")?
+            .query_map(params![key], |row| Ok(((row.get::<_, String>(0)?, row.get::<_, String>(1)?), row.get::<_, Vec<u8>>(2)?)))?
+            .collect::<Result<Vectors, _>>()?)
+    }
+
+    fn get_embedding(&self, key: &str, space: EmbeddingSpace, model: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        let blob: Vec<u8> = self.connection.query_row(
+            "SELECT vector FROM embeddings WHERE path = ?1 AND space = ?2 AND model = ?3",
+            params![key, space.name(), model],
+            |row| row.get(0),
+        )?;
+        Ok(blob_to_embedding(&blob))
+    }
+
+    // Stored state and metadata of a file, None when it isn't in the catalog
+    fn get(&self, key: &str, store: &MetadataStore) -> Result<Option<(FileState, PhotoMeta)>, Box<dyn Error>> {
+        let row = self.connection
+            .query_row(
+                "SELECT mtime, size, sidecar_mtime, metadata FROM photos WHERE path = ?1 AND storage = ?2",
+                params![key, store.storage.name()],
+                |row| {
+                    Ok((
+                        FileState { mtime: row.get(0)?, size: row.get(1)?, sidecar_mtime: row.get(2)? },
+                        row.get::<_, String>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let (state, metadata) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let mut metadata = metadata::parse_metadata(&metadata).unwrap_or_default();
+        let embeddings = self.connection
+            .prepare("SELECT space, model, vector FROM embeddings WHERE path = ?1")?
+            .query_map(params![key], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?)))?
+            .collect::<Result<Vec<(String, String, Vec<u8>)>, _>>()?;
+        for (space, model, vector) in embeddings {
+            if let Some(space) = EmbeddingSpace::ALL.into_iter().find(|s| s.name() == space) {
+                metadata.embeddings_mut(space).entry(model).or_default().vector = blob_to_embedding(&vector);
+            }
+        }
+        Ok(Some((state, metadata)))
+    }
+
+    // Returns the embedding spaces and models whose vector for the file changed, their vector indexes
+    // need rebuilding
+    fn put(&self, key: &str, store: &MetadataStore, state: &FileState, metadata: &PhotoMeta) -> Result<HashSet<(String, String)>, Box<dyn Error>> {
+        let previous_storage: Option<String> = self.connection
+            .query_row("SELECT storage FROM photos WHERE path = ?1", params![key], |row| row.get(0))
+            .optional()?;
+        let previous = self.get_vectors(key)?;
+
+        // Vectors are kept as blobs, not repeated in the JSON
+        let mut stored = metadata.clone();
+        for space in EmbeddingSpace::ALL {
+            stored.embeddings_mut(space).values_mut().for_each(|embedding| embedding.vector.clear());
+        }
+        self.connection.execute(
+            "INSERT OR REPLACE INTO photos (path, mtime, size, sidecar_mtime, storage, people, tags, description, metadata)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                key,
+                state.mtime,
+                state.size,
+                state.sidecar_mtime,
+                store.storage.name(),
+                serde_json::to_string(&metadata.people)?,
+                serde_json::to_string(&metadata.tags)?,
+                metadata.description,
+                serde_json::to_string(&stored)?,
+            ],
+        )?;
+
+        self.connection.execute("DELETE FROM embeddings WHERE path = ?1", params![key])?;
+        let mut vectors = HashMap::new();
+        for space in EmbeddingSpace::ALL {
+            for (model, embedding) in metadata.embeddings(space) {
+                if embedding.vector.is_empty() {
+                    continue;
+                }
+                let blob = embedding_to_blob(&embedding.vector);
+                self.connection.execute(
+                    "INSERT INTO embeddings (path, space, model, vector) VALUES (?1, ?2, ?3, ?4)",
+                    params![key, space.name(), model, blob],
+                )?;
+                vectors.insert((space.name().to_string(), model.clone()), blob);
+            }
+        }
+
+        // A new file, or one last indexed with another storage, is missing from the indexes of this storage
+        let added = previous_storage.as_deref() != Some(store.storage.name());
+        Ok(previous
+            .keys()
+            .chain(vectors.keys())
+            .filter(|model| added || previous.get(*model) != vectors.get(*model))
+            .cloned()
+            .collect())
+    }
+
+    // Add new files and re-read changed ones, then drop rows of files that no longer exist
+    pub fn refresh(&self, store: &MetadataStore, files: &Vec<String>, rebuild: bool) -> Result<RefreshStats, Box<dyn Error>> {
+        let mut stats = RefreshStats::default();
+        let mut changed = HashSet::new();
+        let transaction = self.connection.unchecked_transaction()?;
+        if rebuild {
+            self.connection.execute("DELETE FROM photos", [])?;
+            self.connection.execute("DELETE FROM embeddings", [])?;
+        }
+        let states = self.get_states(store)?;
+
+        let total = files.len();
+        for (count, file) in files.iter().enumerate() {
+            let key = catalog_key(file);
+            let state = match file_state(file) {
+                Ok(state) => state,
+                Err(e) => {
+                    println!("Failed to read {}: {:?}", file, e);
+                    continue;
+                }
+            };
+            let stored = states.get(&key);
+            if stored == Some(&state) {
+                stats.unchanged += 1;
+                continue;
+            }
+
+            println!("{} / {}: {}", count + 1, total, file);
+            let metadata = match metadata::get_metadata(store, file) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    println!("Failed to get metadata for {}: {:?}", file, e);
+                    continue;
+                }
+            };
+            changed.extend(self.put(&key, store, &state, &metadata)?);
+            if stored.is_some() {
+                stats.updated += 1;
+            } else {
+                stats.added += 1;
+            }
+        }
+
+        let paths = self.connection
+            .prepare("SELECT path FROM photos")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        for path in paths {
+            if !Path::new(&path).exists() {
+                changed.extend(self.get_vectors(&path)?.into_keys());
+                self.connection.execute("DELETE FROM photos WHERE path = ?1", params![path])?;
+                self.connection.execute("DELETE FROM embeddings WHERE path = ?1", params![path])?;
+                stats.removed += 1;
+            }
+        }
+
+        self.build_vector_indexes(store, &changed)?;
+
+        transaction.commit()?;
+        Ok(stats)
+    }
+
+    // One vector index per embedding space and model, rebuilt when vectors of the model changed or
+    // it doesn't exist yet
+    fn build_vector_indexes(&self, store: &MetadataStore, changed: &HashSet<(String, String)>) -> Result<(), Box<dyn Error>> {
+        let storage = store.storage.name();
+        let spaces_models = self.connection
+            .prepare(
+                "SELECT DISTINCT embeddings.space, embeddings.model FROM embeddings
+                 JOIN photos ON photos.path = embeddings.path WHERE photos.storage = ?1",
+            )?
+            .query_map(params![storage], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        for table in ["vector_indexes", "vector_index_nodes"] {
+            self.connection.execute(
+                &format!("DELETE FROM {} WHERE storage = ?1 AND (space, model) NOT IN (SELECT space, model FROM embeddings)", table),
+                params![storage],
+            )?;
+        }
+
+        for (space, model) in spaces_models {
+            let exists = self.connection
+                .query_row(
+                    "SELECT 1 FROM vector_indexes WHERE space = ?1 AND model = ?2 AND storage = ?3",
+                    params![space, model, storage],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if exists && !changed.contains(&(space.clone(), model.clone())) {
+                continue;
+            }
+
+            println!("Building {} vector index for {}", space, model);
+            let rows = self.connection
+                .prepare(
+                    "SELECT embeddings.path, embeddings.vector FROM embeddings
+                     JOIN photos ON photos.path = embeddings.path
+                     WHERE embeddings.space = ?1 AND embeddings.model = ?2 AND photos.storage = ?3
+                     ORDER BY embeddings.path",
+                )?
+                .query_map(params![space, model, storage], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+                .collect::<Result<Vec<(String, Vec<u8>)>, _>>()?;
+
+            let mut paths = vec![];
+            let mut index: Option<Hnsw> = None;
+            for (path, blob) in rows {
+                let vector = blob_to_embedding(&blob);
+                let index = index.get_or_insert_with(|| Hnsw::new(vector.len()));
+                match index.insert(&vector) {
+                    Ok(_) => paths.push(path),
+                    Err(e) => println!("Failed to index embedding of {}: {:?}", path, e),
+                }
+            }
+            let index = match index {
+                Some(index) => index,
+                None => continue,
+            };
+
+            self.connection.execute(
+                "DELETE FROM vector_index_nodes WHERE space = ?1 AND model = ?2 AND storage = ?3",
+                params![space, model, storage],
+            )?;
+            self.connection.execute(
+                "INSERT OR REPLACE INTO vector_indexes (space, model, storage, dimension, count, entry_point)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![space, model, storage, index.dimension() as i64, index.len() as i64, index.entry_point()],
+            )?;
+            let mut insert = self.connection.prepare(
+                "INSERT INTO vector_index_nodes (space, model, storage, node, path, vector, links)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for (node, (path, stored)) in paths.iter().zip(index.nodes()).enumerate() {
+                insert.execute(params![
+                    space,
+                    model,
+                    storage,
+                    node as i64,
+                    path,
+                    ann::vector_to_bytes(&stored.vector),
+                    ann::links_to_bytes(&stored.links),
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    // None when there is no index for the model
+    fn vector_index<'a>(&'a self, store: &MetadataStore, space: EmbeddingSpace, model: &'a str) -> Result<Option<StoredIndex<'a>>, Box<dyn Error>> {
+        let header = self.connection
+            .query_row(
+                "SELECT dimension, count, entry_point FROM vector_indexes WHERE space = ?1 AND model = ?2 AND storage = ?3",
+                params![space.name(), model, store.storage.name()],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<i64>>(2)?)),
+            )
+            .optional()?;
+        let (dimension, count, entry_point) = match header {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if entry_point.is_some_and(|node| node < 0 || node >= count) {
+            return Err(format!("Corrupt vector index, entry point outside the {} nodes", count).into());
+        }
+        Ok(Some(StoredIndex {
+            connection: &self.connection,
+            space,
+            model,
+            storage: store.storage.name(),
+            dimension: usize::try_from(dimension)?,
+            count: usize::try_from(count)?,
+            entry_point: entry_point.map(u32::try_from).transpose()?,
+            nodes: RefCell::new(HashMap::new()),
+        }))
+    }
+
+    // The top files most similar to the embedding, None when there is no vector index for the model.
+    // Candidates from the index are re-ranked with their exact similarity, files changed since they
+    // were indexed are compared directly.
+    pub fn nearest(&self, store: &MetadataStore, files: &Vec<String>, space: EmbeddingSpace, model: &str, query: &[f64], top: usize) -> Result<Option<Vec<(String, f64)>>, Box<dyn Error>> {
+        let index = match self.vector_index(store, space, model)? {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let states = self.get_states(store)?;
+        let mut fresh: HashMap<String, String> = HashMap::new();
+        let mut stale = vec![];
+        for file in files {
+            let key = catalog_key(file);
+            match (file_state(file), states.get(&key)) {
+                (Ok(state), Some(stored_state)) if state == *stored_state => {
+                    fresh.insert(key, file.clone());
+                }
+                _ => stale.push(file.clone()),
+            }
+        }
+
+        // The index covers the whole library, widen the search until enough candidates are in files
+        let mut similarity_list: Vec<(String, f64)> = vec![];
+        let mut candidates = (top * CANDIDATE_FACTOR).max(MIN_CANDIDATES);
+        loop {
+            similarity_list.clear();
+            for (node, _distance) in ann::search(&index, query, candidates, candidates)? {
+                let path = index.path(node as u32)?;
+                if let Some(file) = fresh.get(&path) {
+                    let similarity = embedding::cosine_similarity(query, &self.get_embedding(&path, space, model)?);
+                    similarity_list.push((file.clone(), similarity));
+                }
+            }
+            if similarity_list.len() >= top || candidates >= index.len() {
+                break;
+            }
+            candidates *= CANDIDATE_FACTOR;
+        }
+
+        for (file, metadata) in metadata::get_metadata_list(store, &stale)? {
+            if let Some(vector) = metadata.embedding(space, model) {
+                similarity_list.push((file, embedding::cosine_similarity(query, vector)));
+            }
+        }
+
+        similarity_list.sort_by(|a, b| b.1.total_cmp(&a.1));
+        similarity_list.truncate(top);
+        Ok(Some(similarity_list))
+    }
+}
+
+// Metadata from the catalog for files unchanged since they were indexed, read from the files otherwise
+pub fn get_metadata_list(catalog: Option<&Catalog>, store: &MetadataStore, files: &Vec<String>) -> Result<Vec<(String, PhotoMeta)>, Box<dyn Error>> {
+    let catalog = match catalog {
+        Some(catalog) => catalog,
+        None => return metadata::get_metadata_list(store, files),
+    };
+
+    let mut metadata_list = vec![];
+    let mut stale = 0;
+    for file in files {
+        if let (Ok(state), Some((stored_state, metadata))) = (file_state(file), catalog.get(&catalog_key(file), store)?) {
+            if state == stored_state {
+                metadata_list.push((file.clone(), metadata));
+                continue;
+            }
+        }
+
+        stale += 1;
+        match metadata::get_metadata(store, file) {
+            Ok(metadata) => metadata_list.push((file.clone(), metadata)),
+            Err(e) => println!("Failed to get metadata for {}: {:?}", file, e),
+        }
+    }
+
+    if stale > 0 {
+        println!("{} files are missing from the catalog or changed since it was indexed, run index to refresh it", stale);
+    }
+    Ok(metadata_list)
+}
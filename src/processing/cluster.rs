@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+
+use crate::processing::util::fnv1a;
+
+const MAX_ITERATIONS: usize = 100;
+
+// Spherical k-means result, assignments[i] is the cluster of vectors[i]
+pub struct Clustering {
+    pub assignments: Vec<usize>,
+    pub centroids: Vec<Vec<f64>>,
+}
+
+fn normalize(vector: &[f64]) -> Vec<f64> {
+    let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+// Rule of thumb when the number of clusters isn't given
+pub fn default_cluster_count(count: usize) -> usize {
+    ((count as f64 / 2.0).sqrt().round() as usize).max(1)
+}
+
+// Deterministic value in [0, 1), so the same library always gets the same clusters
+fn uniform(seed: usize) -> f64 {
+    (fnv1a(&(seed as u64).to_le_bytes()) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// k-means++ seeding, each next centroid is picked with probability proportional to its squared
+// distance from the nearest centroid picked so far
+fn initial_centroids(vectors: &[Vec<f64>], k: usize) -> Vec<Vec<f64>> {
+    let mut centroids = vec![vectors[(uniform(0) * vectors.len() as f64) as usize].clone()];
+    let mut distances: Vec<f64> = vectors.iter().map(|vector| 1.0 - dot(vector, &centroids[0])).collect();
+    while centroids.len() < k {
+        let total: f64 = distances.iter().map(|d| d * d).sum();
+        if total == 0.0 {
+            break;
+        }
+        let mut target = uniform(centroids.len()) * total;
+        let mut next = vectors.len() - 1;
+        for (i, distance) in distances.iter().enumerate() {
+            target -= distance * distance;
+            if target <= 0.0 {
+                next = i;
+                break;
+            }
+        }
+        centroids.push(vectors[next].clone());
+        for (distance, vector) in distances.iter_mut().zip(vectors) {
+            *distance = distance.min(1.0 - dot(vector, &vectors[next]));
+        }
+    }
+    centroids
+}
+
+// k-means on the unit sphere, vectors are compared by cosine similarity. Vectors must all have the
+// same dimension, k is capped at the number of distinct vectors.
+pub fn kmeans(vectors: &[Vec<f64>], k: usize) -> Clustering {
+    if vectors.is_empty() || k == 0 {
+        return Clustering { assignments: vec![0; vectors.len()], centroids: vec![] };
+    }
+    let vectors: Vec<Vec<f64>> = vectors.iter().map(|vector| normalize(vector)).collect();
+    let mut centroids = initial_centroids(&vectors, k.min(vectors.len()));
+    let mut assignments = vec![usize::MAX; vectors.len()];
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (vector, assignment) in vectors.iter().zip(assignments.iter_mut()) {
+            let nearest = centroids
+                .iter()
+                .enumerate()
+                .max_by(|a, b| dot(vector, a.1).total_cmp(&dot(vector, b.1)))
+                .map(|(i, _centroid)| i)
+                .unwrap_or(0);
+            if *assignment != nearest {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        // An empty cluster keeps its previous centroid
+        let mut sums = vec![vec![0.0; vectors[0].len()]; centroids.len()];
+        for (vector, assignment) in vectors.iter().zip(&assignments) {
+            for (sum, value) in sums[*assignment].iter_mut().zip(vector) {
+                *sum += value;
+            }
+        }
+        for (centroid, sum) in centroids.iter_mut().zip(sums) {
+            if sum.iter().any(|value| *value != 0.0) {
+                *centroid = normalize(&sum);
+            }
+        }
+    }
+    Clustering { assignments, centroids }
+}
+
+impl Clustering {
+    // Members of a cluster, closest to its centroid first
+    pub fn members(&self, vectors: &[Vec<f64>], cluster: usize) -> Vec<usize> {
+        let mut members: Vec<(usize, f64)> = self.assignments
+            .iter()
+            .enumerate()
+            .filter(|(_i, assignment)| **assignment == cluster)
+            .map(|(i, _assignment)| (i, dot(&normalize(&vectors[i]), &self.centroids[cluster])))
+            .collect();
+        members.sort_by(|a, b| b.1.total_cmp(&a.1));
+        members.into_iter().map(|(i, _similarity)| i).collect()
+    }
+}
+
+// Root of the group an item is in, with path halving
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+// Groups of items connected by links, through any number of other items, in order of their first item
+pub fn connected_groups(count: usize, links: impl IntoIterator<Item = (usize, usize)>) -> Vec<Vec<usize>> {
+    let mut parents: Vec<usize> = (0..count).collect();
+    for (a, b) in links {
+        let (root_a, root_b) = (find_root(&mut parents, a), find_root(&mut parents, b));
+        parents[root_b] = root_a;
+    }
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_of_root: Vec<Option<usize>> = vec![None; count];
+    for i in 0..count {
+        let root = find_root(&mut parents, i);
+        match group_of_root[root] {
+            Some(group) => groups[group].push(i),
+            None => {
+                group_of_root[root] = Some(groups.len());
+                groups.push(vec![i]);
+            }
+        }
+    }
+    groups
+}
+
+// BK-tree node, children keyed by their hamming distance to it
+struct BkNode {
+    item: usize,
+    children: BTreeMap<u32, usize>,
+}
+
+// Pairs of hashes within threshold bits of each other, earlier item first. Hashes go into a BK-tree,
+// where a search only visits children whose distance to their parent is within the threshold of the
+// query's, so most of a library is skipped rather than compared pair by pair.
+pub fn hamming_links(hashes: &[u64], threshold: u32) -> Vec<(usize, usize)> {
+    let distance = |a: usize, b: usize| (hashes[a] ^ hashes[b]).count_ones();
+    let mut nodes: Vec<BkNode> = vec![];
+    let mut links = vec![];
+    for item in 0..hashes.len() {
+        if nodes.is_empty() {
+            nodes.push(BkNode { item, children: BTreeMap::new() });
+            continue;
+        }
+
+        let mut pending = vec![0];
+        while let Some(node) = pending.pop() {
+            let node = &nodes[node];
+            let node_distance = distance(node.item, item);
+            if node_distance <= threshold {
+                links.push((node.item, item));
+            }
+            let nearest = node_distance.saturating_sub(threshold);
+            pending.extend(node.children.range(nearest..=node_distance + threshold).map(|(_, &child)| child));
+        }
+
+        let mut node = 0;
+        loop {
+            let node_distance = distance(nodes[node].item, item);
+            match nodes[node].children.get(&node_distance) {
+                Some(&child) => node = child,
+                None => {
+                    let child = nodes.len();
+                    nodes[node].children.insert(node_distance, child);
+                    nodes.push(BkNode { item, children: BTreeMap::new() });
+                    break;
+                }
+            }
+        }
+    }
+    links.sort();
+    links
+}
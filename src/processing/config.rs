@@ -0,0 +1,271 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::ai::provider::{FaceBackend, ProviderConfig};
+use crate::processing::args::{Action, Args};
+use crate::processing::catalog::CATALOG_FILE;
+use crate::processing::metadata::{EmbeddingEncoding, MetadataStore, Storage};
+
+pub const DEFAULT_PROVIDER: &str = "bedrock";
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434/v1";
+pub const DEFAULT_CONFIDENCE: f32 = 85.0;
+pub const DEFAULT_FACE_COLLECTION: &str = "glimpse";
+pub const DEFAULT_TOP: u32 = 10;
+pub const DEFAULT_KEYWORD_WEIGHT: f64 = 0.3;
+pub const DEFAULT_MAX_IMAGE_SIZE: u32 = 1000; // TODO: make a more scientific decision on the resizes
+
+const LIBRARY_CONFIG_FILE: &str = ".glimpse.toml";
+
+// Settings that can come from ~/.config/glimpse/config.toml, a per-library .glimpse.toml or the
+// command line. Every field is optional so layers can be merged, unset fields fall back to defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub provider: Option<String>,
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    pub chat_model: Option<String>,
+    pub vision_model: Option<String>,
+    pub embedding_model: Option<String>,
+    pub image_embedding_model: Option<String>,
+    pub storage: Option<Storage>,
+    // Also write tags, people and description as dc:subject, Iptc4xmpExt:PersonInImage and dc:description
+    pub standard_fields: Option<bool>,
+    // How embedding vectors are written into the metadata
+    pub embedding_encoding: Option<EmbeddingEncoding>,
+    // Longest side, in pixels, of images sent to the provider
+    pub max_image_size: Option<u32>,
+    pub confidence: Option<f32>,
+    // Face collection people are enrolled in, in Rekognition or locally
+    pub face_collection: Option<String>,
+    // Provider face service or local ONNX models, and the local models
+    pub face_backend: Option<FaceBackend>,
+    pub face_detector_model: Option<String>,
+    pub face_embedding_model: Option<String>,
+    pub top: Option<u32>,
+    // Share of keyword matching in find rankings, the rest is semantic similarity
+    pub keyword_weight: Option<f64>,
+    // Where sort-by-tag moves images to, and where discover-faces writes its groups for name-cluster
+    pub sort_directory: Option<String>,
+    pub faces_directory: Option<String>,
+    // Default tag-description prompt, and named prompts selectable with --prompt-name
+    pub prompt: Option<String>,
+    pub prompts: HashMap<String, String>,
+    // SQLite catalog built by the index action
+    pub catalog: Option<String>,
+}
+
+impl Config {
+    // Values set in other take precedence
+    pub fn merge(self, other: Config) -> Config {
+        let mut prompts = self.prompts;
+        prompts.extend(other.prompts);
+        Config {
+            provider: other.provider.or(self.provider),
+            base_url: other.base_url.or(self.base_url),
+            api_key: other.api_key.or(self.api_key),
+            chat_model: other.chat_model.or(self.chat_model),
+            vision_model: other.vision_model.or(self.vision_model),
+            embedding_model: other.embedding_model.or(self.embedding_model),
+            image_embedding_model: other.image_embedding_model.or(self.image_embedding_model),
+            storage: other.storage.or(self.storage),
+            standard_fields: other.standard_fields.or(self.standard_fields),
+            embedding_encoding: other.embedding_encoding.or(self.embedding_encoding),
+            max_image_size: other.max_image_size.or(self.max_image_size),
+            confidence: other.confidence.or(self.confidence),
+            face_collection: other.face_collection.or(self.face_collection),
+            face_backend: other.face_backend.or(self.face_backend),
+            face_detector_model: other.face_detector_model.or(self.face_detector_model),
+            face_embedding_model: other.face_embedding_model.or(self.face_embedding_model),
+            top: other.top.or(self.top),
+            keyword_weight: other.keyword_weight.or(self.keyword_weight),
+            sort_directory: other.sort_directory.or(self.sort_directory),
+            faces_directory: other.faces_directory.or(self.faces_directory),
+            prompt: other.prompt.or(self.prompt),
+            prompts,
+            catalog: other.catalog.or(self.catalog),
+        }
+    }
+
+    pub fn provider_config(&self) -> ProviderConfig {
+        ProviderConfig {
+            name: self.provider.clone().unwrap_or(DEFAULT_PROVIDER.to_string()),
+            base_url: self.base_url.clone().unwrap_or(DEFAULT_BASE_URL.to_string()),
+            api_key: self.api_key.clone().unwrap_or_default(),
+            chat_model: self.chat_model.clone(),
+            vision_model: self.vision_model.clone(),
+            embedding_model: self.embedding_model.clone(),
+            image_embedding_model: self.image_embedding_model.clone(),
+            max_image_size: self.max_image_size(),
+            face_backend: self.face_backend.unwrap_or_default(),
+            face_detector_model: self.face_detector_model.clone(),
+            face_embedding_model: self.face_embedding_model.clone(),
+        }
+    }
+
+    pub fn metadata_store(&self) -> MetadataStore {
+        MetadataStore {
+            storage: self.storage.unwrap_or_default(),
+            standard_fields: self.standard_fields.unwrap_or(false),
+            embedding_encoding: self.embedding_encoding.unwrap_or_default(),
+        }
+    }
+
+    pub fn max_image_size(&self) -> u32 {
+        self.max_image_size.unwrap_or(DEFAULT_MAX_IMAGE_SIZE)
+    }
+
+    pub fn confidence(&self) -> f32 {
+        self.confidence.unwrap_or(DEFAULT_CONFIDENCE)
+    }
+
+    pub fn face_collection(&self) -> String {
+        self.face_collection.clone().unwrap_or(DEFAULT_FACE_COLLECTION.to_string())
+    }
+
+    pub fn top(&self) -> u32 {
+        self.top.unwrap_or(DEFAULT_TOP)
+    }
+
+    pub fn keyword_weight(&self) -> f64 {
+        self.keyword_weight.unwrap_or(DEFAULT_KEYWORD_WEIGHT).clamp(0.0, 1.0)
+    }
+
+    pub fn sort_directory(&self) -> Result<String, Box<dyn Error>> {
+        self.sort_directory
+            .clone()
+            .ok_or("No output directory, pass --output-directory or set sort_directory in the config".into())
+    }
+
+    pub fn faces_directory(&self) -> Result<String, Box<dyn Error>> {
+        self.faces_directory
+            .clone()
+            .ok_or("No output directory, pass --output-directory or set faces_directory in the config".into())
+    }
+
+    // A prompt asked for by name wins over the default prompt (--prompt and --prompt-name can't be
+    // combined), empty means the built-in prompt
+    pub fn prompt(&self, prompt_name: Option<&str>) -> Result<String, Box<dyn Error>> {
+        if let Some(name) = prompt_name {
+            return match self.prompts.get(name) {
+                Some(prompt) => Ok(prompt.clone()),
+                None => Err(format!("Unknown prompt name: {}", name).into()),
+            };
+        }
+        Ok(self.prompt.clone().unwrap_or_default())
+    }
+
+    // Next to the library config when there is one, otherwise in the directory being processed
+    pub fn catalog_path(&self, files: &str) -> PathBuf {
+        if let Some(catalog) = &self.catalog {
+            return PathBuf::from(catalog);
+        }
+        let directory = target_directory(files);
+        library_config_path(&directory)
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+            .unwrap_or(directory)
+            .join(CATALOG_FILE)
+    }
+}
+
+fn read_config(path: &Path) -> Result<Config, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path.display(), e).into())
+}
+
+// ~/.config/glimpse, also home to the local face models and collections
+pub fn user_config_directory() -> Option<PathBuf> {
+    let config_home = std::env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()?;
+    Some(config_home.join("glimpse"))
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    Some(user_config_directory()?.join("config.toml"))
+}
+
+// Directory a files glob points into, the part of the pattern before the first wildcard
+pub fn target_directory(pattern: &str) -> PathBuf {
+    let mut directory = PathBuf::new();
+    for component in Path::new(pattern).components() {
+        if component.as_os_str().to_string_lossy().contains(['*', '?', '[']) {
+            break;
+        }
+        directory.push(component);
+    }
+    if !directory.is_dir() {
+        directory.pop();
+    }
+    if directory.as_os_str().is_empty() {
+        directory.push(".");
+    }
+    std::path::absolute(&directory).unwrap_or(directory)
+}
+
+// Nearest .glimpse.toml in the directory or any of its parents
+fn library_config_path(directory: &Path) -> Option<PathBuf> {
+    directory
+        .ancestors()
+        .map(|dir| dir.join(LIBRARY_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+// User config, overridden by the library config of the files being processed
+pub fn load(files: &str) -> Result<Config, Box<dyn Error>> {
+    let mut config = Config::default();
+    if let Some(path) = user_config_path().filter(|path| path.is_file()) {
+        config = config.merge(read_config(&path)?);
+    }
+    if let Some(path) = library_config_path(&target_directory(files)) {
+        config = config.merge(read_config(&path)?);
+    }
+    Ok(config)
+}
+
+// Settings given on the command line, the top layer
+pub fn from_args(args: &Args) -> Config {
+    let mut config = Config {
+        provider: args.provider.provider.clone(),
+        base_url: args.provider.base_url.clone(),
+        api_key: args.provider.api_key.clone(),
+        chat_model: args.provider.chat_model.clone(),
+        vision_model: args.provider.vision_model.clone(),
+        embedding_model: args.provider.embedding_model.clone(),
+        image_embedding_model: args.provider.image_embedding_model.clone(),
+        max_image_size: args.provider.max_image_size,
+        face_backend: args.provider.face_backend,
+        face_detector_model: args.provider.face_detector_model.clone(),
+        face_embedding_model: args.provider.face_embedding_model.clone(),
+        storage: args.storage,
+        // Only a flag, so it can turn the option on but not off
+        standard_fields: args.standard_fields.then_some(true),
+        embedding_encoding: args.embedding_encoding,
+        ..Config::default()
+    };
+    match &args.action {
+        Action::TagPerson { confidence, .. } => config.confidence = *confidence,
+        Action::EnrollPerson { collection, .. } => config.face_collection = collection.clone(),
+        Action::TagPeople { confidence, collection, .. } => {
+            config.confidence = *confidence;
+            config.face_collection = collection.clone();
+        }
+        Action::TagDescription { prompt, .. } => config.prompt = prompt.clone(),
+        Action::SortByTag { output_directory, .. } => config.sort_directory = output_directory.clone(),
+        Action::NameCluster { output_directory, .. } => config.faces_directory = output_directory.clone(),
+        Action::DiscoverFaces { output_directory, confidence, .. } => {
+            config.faces_directory = output_directory.clone();
+            config.confidence = *confidence;
+        }
+        Action::FindSimilar { top, .. } => config.top = *top,
+        Action::Find { top, keyword_weight, .. } => {
+            config.top = *top;
+            config.keyword_weight = *keyword_weight;
+        }
+        _ => {}
+    }
+    config
+}
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::processing::metadata::BoundingBox;
+
+// Written by discover-faces next to the contact sheets, read by name-cluster
+pub const CLUSTERS_FILE: &str = "face-clusters.json";
+
+// A face found by discover-faces, not yet known to be anyone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveredFace {
+    pub file: String,
+    pub bounding_box: BoundingBox,
+}
+
+// Faces of what is likely one person, cluster is the number shown on its contact sheet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaceCluster {
+    pub cluster: usize,
+    pub faces: Vec<DiscoveredFace>,
+}
+
+pub fn contact_sheet_path(directory: &str, cluster: usize) -> PathBuf {
+    Path::new(directory).join(format!("face-cluster-{}.jpg", cluster))
+}
+
+pub fn write_clusters(directory: &str, clusters: &[FaceCluster]) -> Result<PathBuf, Box<dyn Error>> {
+    let path = Path::new(directory).join(CLUSTERS_FILE);
+    std::fs::write(&path, serde_json::to_string_pretty(clusters)?)?;
+    Ok(path)
+}
+
+pub fn read_clusters(directory: &str) -> Result<Vec<FaceCluster>, Box<dyn Error>> {
+    let path = Path::new(directory).join(CLUSTERS_FILE);
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Can't read {}, run discover-faces first: {}", path.display(), e))?;
+    Ok(serde_json::from_str(&contents)?)
+}
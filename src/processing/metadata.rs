@@ -2,21 +2,468 @@ use little_exif::metadata::Metadata;
 use little_exif::exif_tag::ExifTag;
 use little_exif::u8conversion::*;
 
+use base64::{engine::general_purpose, Engine};
+use clap::ValueEnum;
+use half::f16;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{error::Error, vec};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::processing::xmp::{self, SidecarContent};
+
+// Bump when PhotoMeta changes shape, and teach PhotoMeta::upgrade how to convert older versions
+pub const SCHEMA_VERSION: u32 = 2;
+
+// EXIF lives in a single 64KB APP1 segment, leave room for the tags other than ImageDescription
+const MAX_EXIF_DESCRIPTION_SIZE: usize = 60_000;
+
+// little_exif fails to read images without EXIF with one of these, depending on the format
+const NO_EXIF_ERRORS: [&str; 4] = [
+    "No EXIF data found!",
+    "No metadata found!",
+    "No EXIF chunk according to VP8X flags!",
+    "No EXIF item found!",
+];
+
+// How embedding vectors are written, as base64 of little endian values. Vectors are always f64 in
+// memory, the smaller encodings lose precision that barely moves cosine similarities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingEncoding {
+    /// 4 bytes per value
+    #[default]
+    F32,
+    /// 2 bytes per value
+    F16,
+    /// 1 byte per value, scaled by the largest absolute value
+    Int8,
+}
+
+impl EmbeddingEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingEncoding::F32 => "f32",
+            EmbeddingEncoding::F16 => "f16",
+            EmbeddingEncoding::Int8 => "int8",
+        }
+    }
+
+    fn encode(&self, vector: &[f64]) -> String {
+        let bytes: Vec<u8> = match self {
+            EmbeddingEncoding::F32 => vector.iter().flat_map(|value| (*value as f32).to_le_bytes()).collect(),
+            EmbeddingEncoding::F16 => vector.iter().flat_map(|value| f16::from_f64(*value).to_le_bytes()).collect(),
+            // The scale goes first as an f32, then one signed byte per value
+            EmbeddingEncoding::Int8 => {
+                let scale = vector.iter().fold(0.0, |max: f64, value| max.max(value.abs())) / 127.0;
+                let mut bytes = (scale as f32).to_le_bytes().to_vec();
+                bytes.extend(vector.iter().map(|value| {
+                    let quantized = if scale > 0.0 { (value / scale).round() } else { 0.0 };
+                    quantized as i8 as u8
+                }));
+                bytes
+            }
+        };
+        general_purpose::STANDARD.encode(bytes)
+    }
+
+    fn decode(&self, encoded: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+        let bytes = general_purpose::STANDARD.decode(encoded)?;
+        let vector = match self {
+            EmbeddingEncoding::F32 => bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as f64)
+                .collect(),
+            EmbeddingEncoding::F16 => bytes
+                .chunks_exact(2)
+                .map(|chunk| f16::from_le_bytes([chunk[0], chunk[1]]).to_f64())
+                .collect(),
+            EmbeddingEncoding::Int8 => {
+                if bytes.is_empty() {
+                    return Ok(vec![]);
+                }
+                let scale = f32::from_le_bytes(bytes.get(..4).ok_or("Truncated int8 embedding")?.try_into()?) as f64;
+                bytes[4..].iter().map(|byte| *byte as i8 as f64 * scale).collect()
+            }
+        };
+        Ok(vector)
+    }
+}
+
+// A vector from one embedding model
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "StoredEmbedding", into = "StoredEmbedding")]
+pub struct Embedding {
+    pub vector: Vec<f64>,
+    pub dimension: usize,
+    // Unix seconds, 0 for embeddings written before it was recorded
+    pub created_at: u64,
+    // Encoding the vector is written with
+    pub encoding: EmbeddingEncoding,
+}
+
+impl Embedding {
+    pub fn new(vector: Vec<f64>) -> Embedding {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        Embedding { dimension: vector.len(), vector, created_at, encoding: EmbeddingEncoding::default() }
+    }
+}
+
+// Vectors written before encodings existed are plain JSON arrays
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredVector {
+    Encoded(String),
+    Values(Vec<f64>),
+}
+
+impl Default for StoredVector {
+    fn default() -> Self {
+        StoredVector::Values(vec![])
+    }
+}
+
+// Embedding as it is serialized
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoredEmbedding {
+    vector: StoredVector,
+    dimension: usize,
+    created_at: u64,
+    encoding: EmbeddingEncoding,
+}
+
+impl From<StoredEmbedding> for Embedding {
+    // An undecodable vector is dropped rather than failing the whole metadata, the image can be re-embedded
+    fn from(stored: StoredEmbedding) -> Embedding {
+        let vector = match stored.vector {
+            StoredVector::Values(values) => values,
+            StoredVector::Encoded(encoded) => stored.encoding.decode(&encoded).unwrap_or_else(|e| {
+                println!("Failed to decode {} embedding: {:?}", stored.encoding.name(), e);
+                vec![]
+            }),
+        };
+        Embedding { vector, dimension: stored.dimension, created_at: stored.created_at, encoding: stored.encoding }
+    }
+}
+
+impl From<Embedding> for StoredEmbedding {
+    fn from(embedding: Embedding) -> StoredEmbedding {
+        StoredEmbedding {
+            vector: StoredVector::Encoded(embedding.encoding.encode(&embedding.vector)),
+            dimension: embedding.dimension,
+            created_at: embedding.created_at,
+            encoding: embedding.encoding,
+        }
+    }
+}
+
+// Region of an image, as fractions of its width and height from the top left corner
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BoundingBox {
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    // The whole image
+    pub const FULL: BoundingBox = BoundingBox { left: 0.0, top: 0.0, width: 1.0, height: 1.0 };
+}
+
+// What recognized a face
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FaceSource {
+    Rekognition,
+    // Drawn by hand, in glimpse or another photo manager
+    #[default]
+    Manual,
+    // Computed on this machine
+    Local,
+    // Made up by the mock provider
+    Mock,
+}
+
+// A face recognized as a person
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaceRegion {
+    pub person: String,
+    pub bounding_box: BoundingBox,
+    // 0-100
+    pub similarity: f32,
+    pub source: FaceSource,
+    // Reference image or face collection the face was matched against
+    pub reference: String,
+    // Unix seconds, 0 for regions recorded before it was
+    pub created_at: u64,
+}
+
+impl FaceRegion {
+    pub fn new(person: &str, bounding_box: BoundingBox, similarity: f32, source: FaceSource, reference: &str) -> FaceRegion {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        FaceRegion { person: person.to_string(), bounding_box, similarity, source, reference: reference.to_string(), created_at }
+    }
+}
+
+// Missing fields take their default, so metadata written by older versions still reads
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PhotoMeta {
+    // Metadata written before versioning has no schema_version, it is version 1
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
     pub people: Vec<String>,
     pub description: String,
-    pub description_embedding: Vec<f64>,
-    pub description_embedding_model: String,
+    // Embeddings of the description, by model id, so several models can live side by side
+    pub description_embeddings: BTreeMap<String, Embedding>,
+    // Embeddings of the image itself from multimodal models, in the same space as their text queries
+    pub image_embeddings: BTreeMap<String, Embedding>,
     pub tags: Vec<String>,
+    // Caption found in ImageDescription before glimpse first wrote to it, given back by clear-metadata
+    pub original_description: String,
+    // Automatic album from the cluster action, and the name the LLM gave it
+    pub cluster_id: Option<u32>,
+    pub cluster_name: String,
+    // Where recognized people are in the image
+    pub faces: Vec<FaceRegion>,
+    // People and tags removed by hand, never added back to this image by tag-person, tag and the like
+    pub rejected_people: Vec<String>,
+    pub rejected_tags: Vec<String>,
+    // Fields written by newer versions of glimpse, kept so rewriting the metadata doesn't drop them
+    #[serde(flatten)]
+    pub unknown_fields: serde_json::Map<String, serde_json::Value>,
+    // Version the metadata had when it was read, None for new metadata
+    #[serde(skip)]
+    pub stored_schema_version: Option<u32>,
+}
+
+fn legacy_schema_version() -> u32 {
+    1
+}
+
+impl Default for PhotoMeta {
+    fn default() -> Self {
+        PhotoMeta {
+            schema_version: SCHEMA_VERSION,
+            people: vec![],
+            description: "".to_string(),
+            description_embeddings: BTreeMap::new(),
+            image_embeddings: BTreeMap::new(),
+            tags: vec![],
+            original_description: "".to_string(),
+            cluster_id: None,
+            cluster_name: "".to_string(),
+            faces: vec![],
+            rejected_people: vec![],
+            rejected_tags: vec![],
+            unknown_fields: serde_json::Map::new(),
+            stored_schema_version: None,
+        }
+    }
+}
+
+impl PhotoMeta {
+    // Convert metadata read from disk to the current schema
+    fn upgrade(&mut self) {
+        self.stored_schema_version = Some(self.schema_version);
+        // 1 -> 2: the single description embedding became a map of embeddings by model id. The old
+        // fields end up in unknown_fields and are moved into the map. Every other field added since
+        // (image embeddings, albums, faces, rejections, ...) is empty in older metadata.
+        if self.schema_version < 2 {
+            let vector: Vec<f64> = self.unknown_fields
+                .remove("description_embedding")
+                .and_then(|value| serde_json::from_value(value).ok())
+                .unwrap_or_default();
+            let model = self.unknown_fields
+                .remove("description_embedding_model")
+                .and_then(|value| value.as_str().map(String::from))
+                .unwrap_or_default();
+            if !vector.is_empty() {
+                let embedding = Embedding { dimension: vector.len(), vector, created_at: 0, encoding: EmbeddingEncoding::default() };
+                self.description_embeddings.insert(model, embedding);
+            }
+        }
+        if self.schema_version < SCHEMA_VERSION {
+            self.schema_version = SCHEMA_VERSION;
+        }
+    }
+
+    pub fn embeddings(&self, space: EmbeddingSpace) -> &BTreeMap<String, Embedding> {
+        match space {
+            EmbeddingSpace::Description => &self.description_embeddings,
+            EmbeddingSpace::Image => &self.image_embeddings,
+        }
+    }
+
+    pub fn embeddings_mut(&mut self, space: EmbeddingSpace) -> &mut BTreeMap<String, Embedding> {
+        match space {
+            EmbeddingSpace::Description => &mut self.description_embeddings,
+            EmbeddingSpace::Image => &mut self.image_embeddings,
+        }
+    }
+
+    // Vector from a model, None when the image wasn't embedded with it
+    pub fn embedding(&self, space: EmbeddingSpace, model: &str) -> Option<&[f64]> {
+        self.embeddings(space)
+            .get(model)
+            .map(|embedding| embedding.vector.as_slice())
+            .filter(|vector| !vector.is_empty())
+    }
+
+    // Record where a person's face is, replacing an earlier region of the same person
+    pub fn set_face(&mut self, face: FaceRegion) {
+        self.faces.retain(|region| region.person != face.person);
+        self.faces.push(face);
+    }
+
+    // Remove a person and their face region, and remember they aren't in this image.
+    // Returns whether they were tagged.
+    pub fn reject_person(&mut self, person: &str) -> bool {
+        let tagged = self.people.iter().any(|p| p == person);
+        self.people.retain(|p| p != person);
+        self.faces.retain(|region| region.person != person);
+        if !self.rejects_person(person) {
+            self.rejected_people.push(person.to_string());
+        }
+        tagged
+    }
+
+    // Remove a tag, and remember it doesn't fit this image. Returns whether it was tagged.
+    pub fn reject_tag(&mut self, tag: &str) -> bool {
+        let tagged = self.tags.iter().any(|t| t == tag);
+        self.tags.retain(|t| t != tag);
+        if !self.rejects_tag(tag) {
+            self.rejected_tags.push(tag.to_string());
+        }
+        tagged
+    }
+
+    pub fn rejects_person(&self, person: &str) -> bool {
+        self.rejected_people.iter().any(|p| p == person)
+    }
+
+    pub fn rejects_tag(&self, tag: &str) -> bool {
+        self.rejected_tags.iter().any(|t| t == tag)
+    }
+
+    // Read from disk in an older schema, rewriting it would upgrade the file
+    pub fn needs_migration(&self) -> bool {
+        self.stored_schema_version.is_some_and(|version| version < SCHEMA_VERSION)
+    }
+}
+
+// Fields every version 1 PhotoMeta has, the only glimpse metadata without a schema_version
+const LEGACY_FIELDS: [&str; 5] = ["people", "description", "description_embedding", "description_embedding_model", "tags"];
+
+// None when the text is not glimpse metadata, e.g. a caption written by a camera or editor. Any JSON
+// object would deserialize, so it also needs a schema_version or every field of version 1.
+pub fn parse_metadata(text: &str) -> Option<PhotoMeta> {
+    let value: serde_json::Value = serde_json::from_str(text.trim_end_matches('\0')).ok()?;
+    let object = value.as_object()?;
+    if !object.contains_key("schema_version") && !LEGACY_FIELDS.iter().all(|field| object.contains_key(*field)) {
+        return None;
+    }
+    let mut metadata: PhotoMeta = serde_json::from_value(value).ok()?;
+    if metadata.schema_version > SCHEMA_VERSION {
+        println!("Metadata schema version {} is newer than this version of glimpse supports ({})", metadata.schema_version, SCHEMA_VERSION);
+    }
+    metadata.upgrade();
+    Some(metadata)
+}
+
+// Where PhotoMeta is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// JSON in the EXIF ImageDescription tag of the image itself
+    #[default]
+    Exif,
+    /// .xmp sidecar next to the image, the image itself is never modified
+    Xmp,
+}
+
+impl Storage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Storage::Exif => "exif",
+            Storage::Xmp => "xmp",
+        }
+    }
+}
+
+// What an embedding was generated from, searches compare embeddings of one space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum EmbeddingSpace {
+    /// Embedding of the LLM description
+    #[default]
+    Description,
+    /// Embedding of the image itself from a multimodal model
+    Image,
+}
+
+impl EmbeddingSpace {
+    pub const ALL: [EmbeddingSpace; 2] = [EmbeddingSpace::Description, EmbeddingSpace::Image];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingSpace::Description => "description",
+            EmbeddingSpace::Image => "image",
+        }
+    }
 }
 
-pub fn get_metadata(file: &str) -> Result<PhotoMeta, Box<dyn Error>> {
+// How metadata is read and written for a run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetadataStore {
+    pub storage: Storage,
+    // Also write tags, people and description to standard XMP fields other tools understand
+    pub standard_fields: bool,
+    pub embedding_encoding: EmbeddingEncoding,
+}
+
+// Standard fields found in an xmp sidecar are merged in, whichever tool wrote them. Sidecars are only
+// read when glimpse uses them, so a sidecar it can't parse doesn't break plain EXIF storage.
+pub fn get_metadata(store: &MetadataStore, file: &str) -> Result<PhotoMeta, Box<dyn Error>> {
+    let mut sidecar = if store.storage == Storage::Xmp || store.standard_fields {
+        xmp::read_sidecar(file)?
+    } else {
+        None
+    };
+    let metadata = match store.storage {
+        // A caption that isn't glimpse metadata is kept so writing doesn't destroy it
+        Storage::Exif => get_exif_description(file)?.map(|description| {
+            parse_metadata(&description).unwrap_or_else(|| PhotoMeta {
+                original_description: description.trim_end_matches('\0').to_string(),
+                ..PhotoMeta::default()
+            })
+        }),
+        Storage::Xmp => sidecar.as_mut().and_then(|sidecar| sidecar.photo_meta.take()),
+    };
+
+    let mut metadata = metadata.unwrap_or_default();
+    if let Some(sidecar) = sidecar {
+        sidecar.merge_into(&mut metadata);
+    }
+    Ok(metadata)
+}
+
+// EXIF of an image, empty when it has none yet
+fn read_exif(path: &std::path::Path) -> Result<Metadata, Box<dyn Error>> {
+    match Metadata::new_from_path(path) {
+        Ok(metadata) => Ok(metadata),
+        Err(e) if NO_EXIF_ERRORS.contains(&e.to_string().as_str()) => Ok(Metadata::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Raw text of the ImageDescription tag, None when the image has none
+fn get_exif_description(file: &str) -> Result<Option<String>, Box<dyn Error>> {
     let path = std::path::Path::new(file);
-    let metadata = Metadata::new_from_path(path)?;
+    let metadata = read_exif(path)?;
 
     let description_tag = metadata.get_tag(&ExifTag::ImageDescription(String::new())).next();
     if description_tag.is_some() {
@@ -25,23 +472,22 @@ pub fn get_metadata(file: &str) -> Result<PhotoMeta, Box<dyn Error>> {
             &description_buffer,
             &metadata.get_endian()
         );
-        return Ok(serde_json::from_str(&description)?); 
+        return Ok(Some(description));
     }
 
-    Ok(PhotoMeta {
-        people: vec![],
-        description: "".to_string(),
-        description_embedding: vec![],
-        description_embedding_model: "".to_string(),
-        tags: vec![],
-    })
+    Ok(None)
+}
+
+// None when the image has no ImageDescription tag, or it doesn't hold glimpse metadata
+pub fn get_exif_metadata(file: &str) -> Result<Option<PhotoMeta>, Box<dyn Error>> {
+    Ok(get_exif_description(file)?.and_then(|description| parse_metadata(&description)))
 }
 
 // Return a tuple of the metadata and the file path
-pub fn get_metadata_list(files: &Vec<String>) -> Result<Vec<(String, PhotoMeta)>, Box<dyn Error>> {
+pub fn get_metadata_list(store: &MetadataStore, files: &Vec<String>) -> Result<Vec<(String, PhotoMeta)>, Box<dyn Error>> {
     let mut metadata_list = vec![];
     for file in files {
-        match get_metadata(&file) {
+        match get_metadata(store, &file) {
             Ok(metadata) => metadata_list.push((file.clone(), metadata)),
             // TODO: Verbosity
             Err(e) => println!("Failed to get metadata for {}: {:?}", file, e),
@@ -50,22 +496,80 @@ pub fn get_metadata_list(files: &Vec<String>) -> Result<Vec<(String, PhotoMeta)>
     Ok(metadata_list)
 }
 
-pub async fn write_metadata(file: &str, photo_metadata: PhotoMeta) -> Result<(), Box<dyn Error>> {
+pub async fn write_metadata(store: &MetadataStore, file: &str, mut photo_metadata: PhotoMeta) -> Result<(), Box<dyn Error>> {
+    // Every vector is rewritten in the current encoding
+    for space in EmbeddingSpace::ALL {
+        for embedding in photo_metadata.embeddings_mut(space).values_mut() {
+            embedding.encoding = store.embedding_encoding;
+        }
+    }
+    match store.storage {
+        Storage::Exif => {
+            write_exif_metadata(file, &photo_metadata)?;
+            if store.standard_fields {
+                xmp::write_sidecar(file, &photo_metadata, SidecarContent { photo_meta: false, standard_fields: true })?;
+            }
+            Ok(())
+        }
+        Storage::Xmp => xmp::write_sidecar(file, &photo_metadata, SidecarContent { photo_meta: true, standard_fields: store.standard_fields }),
+    }
+}
+
+fn write_exif_metadata(file: &str, photo_metadata: &PhotoMeta) -> Result<(), Box<dyn Error>> {
     let path = std::path::Path::new(file);
-    let mut metadata = Metadata::new_from_path(path)?;
+    let mut metadata = read_exif(path)?;
 
+    let description = serde_json::to_string(photo_metadata)?;
+    if description.len() > MAX_EXIF_DESCRIPTION_SIZE {
+        return Err(format!(
+            "Metadata is {} bytes, too large for EXIF, use --embedding-encoding int8 or --storage xmp",
+            description.len()
+        ).into());
+    }
     metadata.set_tag(
-        ExifTag::ImageDescription(serde_json::to_string(&photo_metadata)?)
+        ExifTag::ImageDescription(description)
     );
     metadata.write_to_file(path)?;
 
     Ok(())
 }
 
+// Replace the embedded metadata with the caption the image had before, or drop the tag if it had none
+pub fn restore_exif_description(file: &str, original_description: &str) -> Result<(), Box<dyn Error>> {
+    let path = std::path::Path::new(file);
+    let mut metadata = read_exif(path)?;
+
+    if original_description.is_empty() {
+        metadata.remove_tag(ExifTag::ImageDescription(String::new()));
+    } else {
+        metadata.set_tag(ExifTag::ImageDescription(original_description.to_string()));
+    }
+    metadata.write_to_file(path)?;
+
+    Ok(())
+}
+
+// Remove glimpse metadata, images get their original ImageDescription back
+pub fn clear_metadata(store: &MetadataStore, file: &str) -> Result<(), Box<dyn Error>> {
+    match store.storage {
+        Storage::Exif => {
+            // Leave captions written by other tools alone
+            if let Some(metadata) = get_exif_metadata(file)? {
+                restore_exif_description(file, &metadata.original_description)?;
+            }
+            if store.standard_fields {
+                xmp::write_sidecar(file, &PhotoMeta::default(), SidecarContent { photo_meta: false, standard_fields: true })?;
+            }
+            Ok(())
+        }
+        Storage::Xmp => xmp::write_sidecar(file, &PhotoMeta::default(), SidecarContent { photo_meta: true, standard_fields: store.standard_fields }),
+    }
+}
+
 // Implement print
 impl std::fmt::Display for PhotoMeta {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "People: {:?}, Description: {}, Tags: {:?}", self.people, self.description, self.tags)
         // write!(f, "meta: {:?}", self)
     }
-}
\ No newline at end of file
+}
@@ -1,3 +1,11 @@
+pub mod ann;
 pub mod args;
+pub mod catalog;
+pub mod cluster;
+pub mod config;
+pub mod faces;
 pub mod metadata;
-pub mod runner;
\ No newline at end of file
+pub mod query;
+pub mod runner;
+pub mod util;
+pub mod xmp;
\ No newline at end of file
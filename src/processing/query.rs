@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use crate::processing::metadata::PhotoMeta;
+
+// BM25 term frequency saturation and document length normalization
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+// A find query such as `person:Alice tag:beach -tag:work "sunset over water"`. Filters must all
+// hold, the remaining words are matched against descriptions.
+#[derive(Debug, Default, PartialEq)]
+pub struct Query {
+    pub people: Vec<String>,
+    pub excluded_people: Vec<String>,
+    pub tags: Vec<String>,
+    pub excluded_tags: Vec<String>,
+    pub text: String,
+}
+
+// Split on whitespace, keeping double quoted parts together (without the quotes)
+fn split_terms(query: &str) -> Vec<String> {
+    let mut terms = vec![];
+    let mut term = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !term.is_empty() {
+                    terms.push(std::mem::take(&mut term));
+                }
+            }
+            c => term.push(c),
+        }
+    }
+    if !term.is_empty() {
+        terms.push(term);
+    }
+    terms
+}
+
+fn contains_ignore_case(values: &[String], value: &str) -> bool {
+    values.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+// Lowercase words of a text
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Query {
+        let mut parsed = Query::default();
+        let mut text = vec![];
+        for term in split_terms(query) {
+            let (excluded, filter) = match term.strip_prefix('-') {
+                Some(filter) => (true, filter),
+                None => (false, term.as_str()),
+            };
+            let (values, value) = match filter.split_once(':') {
+                Some(("person", name)) if !name.is_empty() => {
+                    (if excluded { &mut parsed.excluded_people } else { &mut parsed.people }, name)
+                }
+                Some(("tag", tag)) if !tag.is_empty() => {
+                    (if excluded { &mut parsed.excluded_tags } else { &mut parsed.tags }, tag)
+                }
+                _ => {
+                    text.push(term.clone());
+                    continue;
+                }
+            };
+            values.push(value.to_string());
+        }
+        parsed.text = text.join(" ");
+        parsed
+    }
+
+    pub fn has_filters(&self) -> bool {
+        !(self.people.is_empty() && self.excluded_people.is_empty() && self.tags.is_empty() && self.excluded_tags.is_empty())
+    }
+
+    // People and tags are compared ignoring case
+    pub fn matches(&self, metadata: &PhotoMeta) -> bool {
+        self.people.iter().all(|person| contains_ignore_case(&metadata.people, person))
+            && !self.excluded_people.iter().any(|person| contains_ignore_case(&metadata.people, person))
+            && self.tags.iter().all(|tag| contains_ignore_case(&metadata.tags, tag))
+            && !self.excluded_tags.iter().any(|tag| contains_ignore_case(&metadata.tags, tag))
+    }
+}
+
+// Okapi BM25 score of each document for the query terms, documents are already tokenized
+pub fn bm25_scores(terms: &[String], documents: &[Vec<String>]) -> Vec<f64> {
+    let count = documents.len() as f64;
+    let average_length = documents.iter().map(|document| document.len()).sum::<usize>() as f64 / count.max(1.0);
+
+    let mut document_frequency: HashMap<&str, f64> = HashMap::new();
+    for term in terms {
+        let frequency = documents.iter().filter(|document| document.contains(term)).count() as f64;
+        document_frequency.insert(term, frequency);
+    }
+
+    documents
+        .iter()
+        .map(|document| {
+            let length = document.len() as f64;
+            terms
+                .iter()
+                .map(|term| {
+                    let frequency = document.iter().filter(|word| *word == term).count() as f64;
+                    if frequency == 0.0 {
+                        return 0.0;
+                    }
+                    let n = document_frequency[term.as_str()];
+                    let idf = ((count - n + 0.5) / (n + 0.5) + 1.0).ln();
+                    idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length / average_length.max(1.0)))
+                })
+                .sum()
+        })
+        .collect()
+}
@@ -1,35 +1,72 @@
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::result::Result;
 use std::result::Result::Ok;
 use glob::glob;
 
-use crate::processing::metadata;
-use crate::ai::{vision, llm, embedding};
-use crate::processing::args;
+use crate::processing::catalog::{self, Catalog, CANDIDATE_FACTOR};
+use crate::processing::cluster;
+use crate::processing::query::{self, Query};
+use crate::processing::faces::{self, FaceCluster};
+use crate::processing::metadata::{self, BoundingBox, Embedding, EmbeddingSpace, FaceRegion, FaceSource, MetadataStore, Storage};
+use crate::processing::util;
+use crate::processing::xmp;
+use crate::ai::{llm, embedding};
+use crate::ai::provider::{self, Capability, FaceBackend, Provider, ProviderConfig};
+use crate::processing::args::{Action, Args};
+use crate::processing::config;
+use crate::graphics::images::{self, ImageHash};
 
-async fn tag_person(provider: &str, reference_file: &str, files: Vec<String>, person_name: &str, confidence: f32) -> Result<(), Box<dyn Error>> {
-    // bedrock is only provider supports this for now
-    if provider != "bedrock" {
-        println!("Provider {} not supported for tag-person", provider);
-        return Ok(());
+// The reference face to compare with, the reference itself when it holds a single face, otherwise a
+// temp crop of the chosen face. Returns whether the file is a temp file to clear.
+async fn reference_face(provider: &dyn Provider, reference_file: &str, face_index: Option<usize>) -> Result<(String, bool), Box<dyn Error>> {
+    let faces = provider.detect_faces(reference_file).await?;
+    match (faces.len(), face_index) {
+        (0, _) => Err(format!("No face found in {}", reference_file).into()),
+        (_, Some(face_index)) => {
+            let face = faces
+                .get(face_index)
+                .ok_or(format!("{} has {} faces, --face-index must be below that", reference_file, faces.len()))?;
+            Ok((images::crop_face_temp_image(reference_file, face)?, true))
+        }
+        (1, None) => Ok((reference_file.to_string(), false)),
+        (count, None) => {
+            println!("{} has {} faces, from left to right:", reference_file, count);
+            for (i, face) in faces.iter().enumerate() {
+                println!("  {}: left {:.0}%, top {:.0}%", i, face.left * 100.0, face.top * 100.0);
+            }
+            Err("Ambiguous reference image, pass --face-index to choose the person's face".into())
+        }
     }
+}
 
+async fn tag_person(provider: &dyn Provider, store: &MetadataStore, reference_file: &str, face_index: Option<usize>, files: Vec<String>, person_name: &str, confidence: f32) -> Result<(), Box<dyn Error>> {
+    let source = provider.face_source().ok_or_else(|| provider.unsupported(Capability::CompareFaces))?;
+    let (reference, temporary) = reference_face(provider, reference_file, face_index).await?;
     let total = files.len();
     let mut count = 0;
     for file in files {
         count += 1;
         println!("{} / {}: {}", count, total, file);
 
-        match metadata::get_metadata(&file) {
+        match metadata::get_metadata(store, &file) {
             Ok(mut metadata) => {
                 if metadata.people.contains(&person_name.to_string()) {
                     println!("{} is already tagged in {}", person_name, file);
+                } else if metadata.rejects_person(person_name) {
+                    println!("{} was untagged from {}, skipping", person_name, file);
                 } else {
-                    match vision::compare_faces(reference_file, &file).await {
-                        Ok(similarity) => {
-                            if similarity >= confidence { // TODO: check if this is right threshold?
+                    // Every face of the target is considered, the best match above the confidence is the person
+                    match provider.compare_faces(&reference, &file).await {
+                        Ok(faces) => {
+                            let best = faces
+                                .into_iter()
+                                .filter(|(_bounding_box, similarity)| *similarity >= confidence)
+                                .max_by(|a, b| a.1.total_cmp(&b.1));
+                            if let Some((bounding_box, similarity)) = best {
                                 metadata.people.push(person_name.to_string());
-                                if let Err(e) = metadata::write_metadata(&file, metadata).await {
+                                metadata.set_face(FaceRegion::new(person_name, bounding_box, similarity, source, reference_file));
+                                if let Err(e) = metadata::write_metadata(store, &file, metadata).await {
                                     println!("Failed to write metadata for {}: {:?}", file, e);
                                 } else {
                                     println!("Tagged {} in {}", person_name, file);
@@ -43,17 +80,160 @@ async fn tag_person(provider: &str, reference_file: &str, files: Vec<String>, pe
             Err(e) => println!("Failed to get metadata for {}: {:?}", file, e),
         }
     }
+    if temporary {
+        images::clear_temp_file(&reference)?;
+    }
     Ok(())
 }
 
-async fn find_person(provider: &str, files: Vec<String>, person_name: &str) -> Result<(), Box<dyn Error>> {
-    // bedrock is only provider supports this for now
-    if provider != "bedrock" {
-        println!("Provider {} not supported for find-person", provider);
-        return Ok(());
+// Remove the person and their face region, recorded so face tagging never adds them back
+async fn untag_person(store: &MetadataStore, files: Vec<String>, person_name: &str) -> Result<(), Box<dyn Error>> {
+    for file in files {
+        let mut metadata = match metadata::get_metadata(store, &file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", file, e);
+                continue;
+            }
+        };
+        if metadata.rejects_person(person_name) && !metadata.people.iter().any(|person| person == person_name) {
+            println!("{} is already untagged from {}", person_name, file);
+            continue;
+        }
+        let tagged = metadata.reject_person(person_name);
+        match metadata::write_metadata(store, &file, metadata).await {
+            Ok(_) if tagged => println!("Untagged {} from {}", person_name, file),
+            Ok(_) => println!("{} was not tagged in {}, it won't be", person_name, file),
+            Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
+        }
+    }
+    Ok(())
+}
+
+async fn enroll_person(provider: &dyn Provider, collection: &str, reference_files: Vec<String>, person_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut enrolled = 0;
+    for file in reference_files {
+        match provider.enroll_face(collection, person_name, &file).await {
+            Ok(0) => println!("No usable face found in {}", file),
+            Ok(faces) => {
+                enrolled += faces;
+                println!("Enrolled {} from {}", person_name, file);
+            }
+            Err(e) => println!("Failed to enroll {} from {}: {:?}", person_name, file, e),
+        }
+    }
+    println!("Enrolled {} faces of {} in {}", enrolled, person_name, collection);
+    Ok(())
+}
+
+// Every enrolled person is searched for at once, unlike tag-person which compares one reference
+async fn tag_people(provider: &dyn Provider, store: &MetadataStore, files: Vec<String>, collection: &str, confidence: f32) -> Result<(), Box<dyn Error>> {
+    let total = files.len();
+    let mut count = 0;
+    for file in files {
+        count += 1;
+        println!("{} / {}: {}", count, total, file);
+
+        let mut metadata = match metadata::get_metadata(store, &file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", file, e);
+                continue;
+            }
+        };
+        let people = match provider.search_faces(collection, &file, confidence).await {
+            Ok(people) => people,
+            Err(e) => {
+                println!("Failed to search faces for {}: {:?}", file, e);
+                continue;
+            }
+        };
+
+        let mut tagged = vec![];
+        for face in people {
+            if !metadata.people.contains(&face.person) && !metadata.rejects_person(&face.person) {
+                metadata.people.push(face.person.clone());
+                tagged.push(face.person.clone());
+                metadata.set_face(face);
+            }
+        }
+        if tagged.is_empty() {
+            continue;
+        }
+        match metadata::write_metadata(store, &file, metadata).await {
+            Ok(_) => println!("Tagged {} in {}", tagged.join(", "), file),
+            Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
+        }
     }
+    Ok(())
+}
+
+// Group every face across the images, and write a contact sheet per group for name-cluster
+async fn discover_faces(provider: &dyn Provider, files: Vec<String>, output_directory: &str, confidence: f32, min_faces: usize) -> Result<(), Box<dyn Error>> {
+    let mut groups = provider.discover_faces(&files, confidence).await?;
+    groups.retain(|group| group.len() >= min_faces);
+    groups.sort_by(|a, b| b.len().cmp(&a.len()));
+
+    std::fs::create_dir_all(output_directory)?;
+    let mut clusters = vec![];
+    for (cluster, mut group) in groups.into_iter().enumerate() {
+        // Stored by canonical path, so name-cluster finds the files from any working directory
+        for face in &mut group {
+            if let Ok(path) = std::fs::canonicalize(&face.file) {
+                face.file = path.to_string_lossy().to_string();
+            }
+        }
+        let sheet = faces::contact_sheet_path(output_directory, cluster);
+        let crops: Vec<(String, BoundingBox)> = group.iter().map(|face| (face.file.clone(), face.bounding_box)).collect();
+        match images::contact_sheet(&crops, &sheet.to_string_lossy()) {
+            Ok(_) => println!("Cluster {}: {} faces, {}", cluster, group.len(), sheet.display()),
+            Err(e) => println!("Failed to write contact sheet for cluster {}: {:?}", cluster, e),
+        }
+        clusters.push(FaceCluster { cluster, faces: group });
+    }
+    let path = faces::write_clusters(output_directory, &clusters)?;
+    println!("Found {} people, name them with name-cluster --output-directory {} --cluster N --person NAME", clusters.len(), output_directory);
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+// Tag the person in every image of a discovered face group
+async fn name_cluster(store: &MetadataStore, output_directory: &str, cluster: usize, person_name: &str) -> Result<(), Box<dyn Error>> {
+    let clusters = faces::read_clusters(output_directory)?;
+    let cluster = clusters
+        .into_iter()
+        .find(|face_cluster| face_cluster.cluster == cluster)
+        .ok_or(format!("No cluster {} in {}", cluster, output_directory))?;
+    let reference = format!("face cluster {}", cluster.cluster);
 
-    let files_metadata = metadata::get_metadata_list(&files)?;
+    for face in cluster.faces {
+        let mut metadata = match metadata::get_metadata(store, &face.file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", face.file, e);
+                continue;
+            }
+        };
+        // Groups can hold faces of other people, a person untagged by hand stays untagged
+        if metadata.rejects_person(person_name) {
+            println!("{} was untagged from {}, skipping", person_name, face.file);
+            continue;
+        }
+        if !metadata.people.iter().any(|person| person == person_name) {
+            metadata.people.push(person_name.to_string());
+        }
+        // Named by hand from the contact sheet
+        metadata.set_face(FaceRegion::new(person_name, face.bounding_box, 100.0, FaceSource::Manual, &reference));
+        match metadata::write_metadata(store, &face.file, metadata).await {
+            Ok(_) => println!("Tagged {} in {}", person_name, face.file),
+            Err(e) => println!("Failed to write metadata for {}: {:?}", face.file, e),
+        }
+    }
+    Ok(())
+}
+
+async fn find_person(catalog: Option<&Catalog>, store: &MetadataStore, files: Vec<String>, person_name: &str) -> Result<(), Box<dyn Error>> {
+    let files_metadata = catalog::get_metadata_list(catalog, store, &files)?;
     for (file, metadata) in files_metadata {
         if metadata.people.contains(&person_name.to_string()) {
             println!("{}", file);
@@ -62,23 +242,17 @@ async fn find_person(provider: &str, files: Vec<String>, person_name: &str) -> R
     Ok(())
 }
 
-async fn show_metadata(files: Vec<String>) -> Result<(), Box<dyn Error>> {
-    let files_metadata = metadata::get_metadata_list(&files)?;
+async fn show_metadata(catalog: Option<&Catalog>, store: &MetadataStore, files: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let files_metadata = catalog::get_metadata_list(catalog, store, &files)?;
     for (file, metadata) in files_metadata {
         println!("{}: {}", file, metadata);
     }
     Ok(())
 }
 
-async fn clear_metadata(files: Vec<String>) -> Result<(), Box<dyn Error>> {
+async fn clear_metadata(store: &MetadataStore, files: Vec<String>) -> Result<(), Box<dyn Error>> {
     for file in files {
-        match metadata::write_metadata(&file, metadata::PhotoMeta {
-            people: vec![],
-            description: "".to_string(),
-            description_embedding: vec![],
-            description_embedding_model: "".to_string(),
-            tags: vec![],
-        }).await {
+        match metadata::clear_metadata(store, &file) {
             Ok(_) => println!("Cleared metadata for {}", file),
             Err(e) => println!("Failed to clear metadata for {}: {:?}", file, e),
         }
@@ -86,8 +260,18 @@ async fn clear_metadata(files: Vec<String>) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn sort_by_tag(files: Vec<String>, output_directory: &str) -> Result<(), Box<dyn Error>> {
-    let files_metadata = metadata::get_metadata_list(&files)?;
+// Move an image and its sidecar, if it has one, so metadata kept in xmp goes along
+fn move_with_sidecar(file: &str, new_file: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    std::fs::rename(file, new_file)?;
+    let sidecar = xmp::sidecar_path(file);
+    if sidecar.is_file() {
+        std::fs::rename(&sidecar, xmp::sidecar_path(&new_file.to_string_lossy()))?;
+    }
+    Ok(())
+}
+
+async fn sort_by_tag(store: &MetadataStore, files: Vec<String>, output_directory: &str) -> Result<(), Box<dyn Error>> {
+    let files_metadata = metadata::get_metadata_list(store, &files)?;
     // Get list of tags
     let mut tags: Vec<String> = vec![];
     for (_file, metadata) in &files_metadata {
@@ -121,7 +305,7 @@ async fn sort_by_tag(files: Vec<String>, output_directory: &str) -> Result<(), B
         let tag = &metadata.tags[0];  // TODO: handle multiple tags somehow?
         let path = std::path::Path::new(output_directory).join(tag);
         let new_file = path.join(std::path::Path::new(&file).file_name().unwrap());
-        match std::fs::rename(&file, &new_file) {
+        match move_with_sidecar(&file, &new_file) {
             Ok(_) => println!("Moved {} to {}", file, new_file.display()),
             Err(e) => println!("Failed to move {} to {}: {:?}", file, new_file.display(), e),
         }
@@ -130,7 +314,7 @@ async fn sort_by_tag(files: Vec<String>, output_directory: &str) -> Result<(), B
     Ok(())
 }
 
-async fn tag_description(provider: &str, files: Vec<String>, overwrite: bool, prompt: &str) -> Result<(), Box<dyn Error>> {
+async fn tag_description(provider: &dyn Provider, store: &MetadataStore, files: Vec<String>, overwrite: bool, prompt: &str, max_image_size: u32) -> Result<(), Box<dyn Error>> {
     let total = files.len();
     let mut count = 0;
     for file in files {
@@ -138,7 +322,7 @@ async fn tag_description(provider: &str, files: Vec<String>, overwrite: bool, pr
         println!("{} / {}: {}", count, total, file);
 
         // Load original metadata
-        let mut metadata = match metadata::get_metadata(&file) {
+        let mut metadata = match metadata::get_metadata(store, &file) {
             Ok(metadata) => metadata,
             Err(e) => {
                 println!("Failed to get metadata for {}: {:?}", file, e);
@@ -152,7 +336,7 @@ async fn tag_description(provider: &str, files: Vec<String>, overwrite: bool, pr
         }
 
         // Get description from AI, including additional context (people in the photo)
-        let description = match llm::describe_image(&provider, &file, &metadata, &prompt).await {
+        let description = match llm::describe_image(provider, &file, &metadata, &prompt, max_image_size).await {
             Ok(description) => description,
             Err(e) => {
                 println!("Failed to describe image for {}: {:?}", file, e);
@@ -160,7 +344,7 @@ async fn tag_description(provider: &str, files: Vec<String>, overwrite: bool, pr
             }
         };
         // Now generate embedding for the description
-        let (description_embedding_model, embedding_result) = embedding::generate_embedding(&provider, description.clone()).await;
+        let (description_embedding_model, embedding_result) = embedding::generate_embedding(provider, description.clone()).await;
         let description_embedding = match embedding_result {
             Ok(embedding) => embedding,
             Err(e) => {
@@ -169,12 +353,12 @@ async fn tag_description(provider: &str, files: Vec<String>, overwrite: bool, pr
             }
         };
 
+        // Only the current model's embedding is replaced, re-embed --force refreshes the others
         metadata.description = description;
-        metadata.description_embedding = description_embedding;
-        metadata.description_embedding_model = description_embedding_model;
+        metadata.description_embeddings.insert(description_embedding_model, Embedding::new(description_embedding));
 
         // Write updated metadata
-        match metadata::write_metadata(&file, metadata).await {
+        match metadata::write_metadata(store, &file, metadata).await {
             Ok(_) => println!("Tagged description for {}", file),
             Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
         }
@@ -182,14 +366,7 @@ async fn tag_description(provider: &str, files: Vec<String>, overwrite: bool, pr
     Ok(())
 }
 
-async fn tag(provider: &str, files: Vec<String>, tags: &Vec<String>, overwrite: bool) -> Result<(), Box<dyn Error>> {
-    // bedrock is only provider supports this for now
-    // TODO: add support for openai
-    if provider != "bedrock" {
-        println!("Provider {} not supported for tag", provider);
-        return Ok(());
-    }
-
+async fn tag(provider: &dyn Provider, store: &MetadataStore, files: Vec<String>, tags: &Vec<String>, overwrite: bool) -> Result<(), Box<dyn Error>> {
     let total = files.len();
     let mut count = 0;
     for file in files {
@@ -197,7 +374,7 @@ async fn tag(provider: &str, files: Vec<String>, tags: &Vec<String>, overwrite:
         println!("{} / {}: {}", count, total, file);
 
         // Load original metadata
-        let mut metadata = match metadata::get_metadata(&file) {
+        let mut metadata = match metadata::get_metadata(store, &file) {
             Ok(metadata) => metadata,
             Err(e) => {
                 println!("Failed to get metadata for {}: {:?}", file, e);
@@ -209,8 +386,15 @@ async fn tag(provider: &str, files: Vec<String>, tags: &Vec<String>, overwrite:
             metadata.tags = vec![];
         }
 
+        // Tags removed from the image by hand aren't offered again
+        let tags: Vec<String> = tags.iter().filter(|tag| !metadata.rejects_tag(tag)).cloned().collect();
+        if tags.is_empty() {
+            println!("Every tag was removed from {}, skipping", file);
+            continue;
+        }
+
         // Get tag from AI
-        let tag = match llm::tag_metadata(&provider, &metadata, tags).await {
+        let tag = match llm::tag_metadata(provider, &metadata, &tags).await {
             Ok(tag) => tag,
             Err(e) => {
                 println!("Failed to tag from metadata for {}: {:?}", file, e);
@@ -227,7 +411,7 @@ async fn tag(provider: &str, files: Vec<String>, tags: &Vec<String>, overwrite:
         }
 
         // Write updated metadata
-        match metadata::write_metadata(&file, metadata).await {
+        match metadata::write_metadata(store, &file, metadata).await {
             Ok(_) => println!("Tagged metadata for {}", file),
             Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
         }
@@ -235,13 +419,128 @@ async fn tag(provider: &str, files: Vec<String>, tags: &Vec<String>, overwrite:
     Ok(())
 }
 
+// Remove the tags, recorded so tag and cluster --as-tag never add them back
+async fn untag(store: &MetadataStore, files: Vec<String>, tags: &[String]) -> Result<(), Box<dyn Error>> {
+    for file in files {
+        let mut metadata = match metadata::get_metadata(store, &file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", file, e);
+                continue;
+            }
+        };
+        let mut removed = vec![];
+        for tag in tags {
+            if metadata.reject_tag(tag) {
+                removed.push(tag.as_str());
+            }
+        }
+        match metadata::write_metadata(store, &file, metadata).await {
+            Ok(_) if removed.is_empty() => println!("No tags removed from {}", file),
+            Ok(_) => println!("Removed {} from {}", removed.join(", "), file),
+            Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
+        }
+    }
+    Ok(())
+}
 
-async fn find_similar(reference_file: &str, files: Vec<String>, top: u32) -> Result<(), Box<dyn Error>> {
-    // Load original metadata
-    let reference_metadata = metadata::get_metadata(&reference_file)?;
 
-    // Now load metadata for all other files
-    let files_metadata: Vec<(String, metadata::PhotoMeta)> = match metadata::get_metadata_list(&files) {
+// Embedding models the files were embedded with in a space
+fn embedding_models(files_metadata: &[(String, metadata::PhotoMeta)], space: EmbeddingSpace) -> Vec<String> {
+    let mut models: Vec<String> = vec![];
+    for (_file, metadata) in files_metadata {
+        for model in metadata.embeddings(space).keys() {
+            if !models.contains(model) {
+                models.push(model.clone());
+            }
+        }
+    }
+    models
+}
+
+// Query embeddings by model, the first ones are preferred for files embedded with several models
+type Queries = Vec<(String, Vec<f64>)>;
+
+// The query and file vectors of the first query model the file was embedded with
+fn query_for<'a>(queries: &'a Queries, metadata: &'a metadata::PhotoMeta, space: EmbeddingSpace) -> Option<(&'a [f64], &'a [f64])> {
+    queries
+        .iter()
+        .find_map(|(model, query)| metadata.embedding(space, model).map(|vector| (query.as_slice(), vector)))
+}
+
+// What a search compares the files with
+enum SearchInput<'a> {
+    Text(&'a str),
+    Image(&'a str),
+}
+
+// Embed the search input with each of the models, models it can't be embedded with are skipped with a warning
+async fn embed_with_models(provider_config: &ProviderConfig, space: EmbeddingSpace, models: &[String], input: &SearchInput<'_>) -> Queries {
+    let mut embeddings = vec![];
+    for model in models {
+        // Only embeddings are needed, so the face models of the local backend are never loaded
+        let provider_config = ProviderConfig { face_backend: FaceBackend::Provider, ..provider_config.clone() };
+        let config = match space {
+            EmbeddingSpace::Description => ProviderConfig { embedding_model: Some(model.clone()), ..provider_config.clone() },
+            EmbeddingSpace::Image => ProviderConfig { image_embedding_model: Some(model.clone()), ..provider_config.clone() },
+        };
+        let provider = match provider::get_provider(&config).await {
+            Ok(provider) => provider,
+            Err(e) => {
+                println!("Skipping files embedded with {}: {:?}", model, e);
+                continue;
+            }
+        };
+        let result = match input {
+            SearchInput::Text(text) => embedding::generate_query_embedding(provider.as_ref(), space, text).await,
+            SearchInput::Image(file) => embedding::generate_image_embedding(provider.as_ref(), file, provider_config.max_image_size).await,
+        };
+        match result {
+            (model, Ok(embedding)) => {
+                embeddings.push((model, embedding));
+            }
+            (model, Err(e)) => println!("Skipping files embedded with {}, failed to embed the query with it: {:?}", model, e),
+        }
+    }
+    embeddings
+}
+
+// Add query embeddings for every other model the files were embedded with
+async fn add_other_models(catalog: Option<&Catalog>, store: &MetadataStore, provider_config: &ProviderConfig, files: &Vec<String>, space: EmbeddingSpace, input: &SearchInput<'_>, queries: &mut Queries) -> Result<(), Box<dyn Error>> {
+    let models: Vec<String> = embedding_models(&catalog::get_metadata_list(catalog, store, files)?, space)
+        .into_iter()
+        .filter(|model| !queries.iter().any(|(query_model, _query)| query_model == model))
+        .collect();
+    queries.extend(embed_with_models(provider_config, space, &models, input).await);
+    Ok(())
+}
+
+// Files most similar to the query embeddings, each file is compared with the query embedding of its
+// first model it was embedded with. Uses the catalog's vector indexes when it has one for every model.
+fn most_similar(catalog: Option<&Catalog>, store: &MetadataStore, files: &Vec<String>, space: EmbeddingSpace, queries: &Queries, top: u32) -> Result<Vec<(String, f64)>, Box<dyn Error>> {
+    if let Some(catalog) = catalog {
+        let mut similarity_list: Vec<(String, f64)> = vec![];
+        let mut indexed = true;
+        for (model, query) in queries {
+            match catalog.nearest(store, files, space, model, query, top as usize)? {
+                Some(nearest) => similarity_list.extend(nearest),
+                None => {
+                    indexed = false;
+                    break;
+                }
+            }
+        }
+        if indexed {
+            // A file embedded with several models is found once per model, keep its best match
+            similarity_list.sort_by(|a, b| b.1.total_cmp(&a.1));
+            let mut seen = HashSet::new();
+            similarity_list.retain(|(file, _similarity)| seen.insert(file.clone()));
+            similarity_list.truncate(top as usize);
+            return Ok(similarity_list);
+        }
+    }
+
+    let files_metadata: Vec<(String, metadata::PhotoMeta)> = match catalog::get_metadata_list(catalog, store, files) {
         Ok(metadata) => metadata,
         Err(e) => {
             println!("Failed to get metadata list: {:?}", e);
@@ -249,17 +548,65 @@ async fn find_similar(reference_file: &str, files: Vec<String>, top: u32) -> Res
         }
     };
 
-    // Now generate similarity list
+    // Now generate similarity list, only vectors from the same model are comparable
     let mut similarity_list: Vec<(String, f64)> = vec![];
+    let mut mismatched = 0;
     for (file, metadata) in files_metadata {
-        let similarity = embedding::cosine_similarity(&reference_metadata.description_embedding, &metadata.description_embedding);
+        if metadata.embeddings(space).is_empty() {
+            continue;
+        }
+        let (query, vector) = match query_for(queries, &metadata, space) {
+            Some(vectors) => vectors,
+            None => {
+                mismatched += 1;
+                continue;
+            }
+        };
+        let similarity = embedding::cosine_similarity(query, vector);
         similarity_list.push((file, similarity));
     }
+    if mismatched > 0 {
+        println!("Skipped {} files with embeddings from other models, pass --all-models to search them too", mismatched);
+    }
     // Sort by similarity
     similarity_list.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
     if similarity_list.len() > top as usize {
         similarity_list.truncate(top as usize);
     }
+    Ok(similarity_list)
+}
+
+async fn find_similar(catalog: Option<&Catalog>, store: &MetadataStore, provider_config: &ProviderConfig, reference_file: &str, files: Vec<String>, top: u32, space: EmbeddingSpace, all_models: bool) -> Result<(), Box<dyn Error>> {
+    // Load original metadata
+    let reference_metadata = metadata::get_metadata(store, &reference_file)?;
+
+    // Compare with the reference embedding of the chosen model, or of every model it was embedded with
+    let chosen_model = match space {
+        EmbeddingSpace::Description => provider_config.embedding_model.as_deref(),
+        EmbeddingSpace::Image => provider_config.image_embedding_model.as_deref(),
+    };
+    let mut queries: Queries = reference_metadata
+        .embeddings(space)
+        .iter()
+        .filter(|(model, embedding)| chosen_model.map_or(true, |chosen| chosen == *model) && !embedding.vector.is_empty())
+        .map(|(model, embedding)| (model.clone(), embedding.vector.clone()))
+        .collect();
+    // Other models are compared through the reference description or image embedded with them
+    if all_models {
+        let input = match space {
+            EmbeddingSpace::Description => SearchInput::Text(&reference_metadata.description),
+            EmbeddingSpace::Image => SearchInput::Image(reference_file),
+        };
+        if space == EmbeddingSpace::Image || !reference_metadata.description.is_empty() {
+            add_other_models(catalog, store, provider_config, &files, space, &input, &mut queries).await?;
+        }
+    }
+    if queries.is_empty() {
+        println!("{} has no {} embedding to compare with", reference_file, space.name());
+        return Ok(());
+    }
+
+    let similarity_list = most_similar(catalog, store, &files, space, &queries, top)?;
 
     // Print
     for (file, similarity) in similarity_list.iter() {
@@ -269,28 +616,64 @@ async fn find_similar(reference_file: &str, files: Vec<String>, top: u32) -> Res
     Ok(())
 }
 
-async fn find(provider: &str, files: Vec<String>, description: &str, top: u32) -> Result<(), Box<dyn Error>> {
-    // Load metadata for all  files
-    let files_metadata: Vec<(String, metadata::PhotoMeta)> = match metadata::get_metadata_list(&files) {
-        Ok(metadata) => metadata,
-        Err(e) => {
-            println!("Failed to get metadata list: {:?}", e);
-            return Err(e);
+async fn find(provider: &dyn Provider, catalog: Option<&Catalog>, store: &MetadataStore, provider_config: &ProviderConfig, files: Vec<String>, query: &str, top: u32, keyword_weight: f64, space: EmbeddingSpace, all_models: bool) -> Result<(), Box<dyn Error>> {
+    let query = Query::parse(query);
+
+    // Only filters, list every match
+    if query.text.is_empty() {
+        for (file, metadata) in catalog::get_metadata_list(catalog, store, &files)? {
+            if query.matches(&metadata) {
+                println!("{}", file);
+            }
         }
+        return Ok(());
+    }
+
+    // Generate embedding for the text, in the image space it is matched against the images themselves
+    let (model, embedding_result) = embedding::generate_query_embedding(provider, space, &query.text).await;
+    let mut queries: Queries = vec![(model, embedding_result?)];
+    if all_models {
+        add_other_models(catalog, store, provider_config, &files, space, &SearchInput::Text(&query.text), &mut queries).await?;
+    }
+
+    // Filters are checked on every file, without them the vector index narrows the library down to
+    // the best semantic matches first
+    let candidates: Vec<String> = if query.has_filters() || catalog.is_none() {
+        files
+    } else {
+        most_similar(catalog, store, &files, space, &queries, top * CANDIDATE_FACTOR as u32)?
+            .into_iter()
+            .map(|(file, _similarity)| file)
+            .collect()
     };
+    // Only vectors from the same model are comparable, files embedded with other models are left out
+    // rather than ranked on their keywords alone
+    let (files_metadata, mismatched): (Vec<_>, Vec<_>) = catalog::get_metadata_list(catalog, store, &candidates)?
+        .into_iter()
+        .filter(|(_file, metadata)| query.matches(metadata))
+        .partition(|(_file, metadata)| metadata.embeddings(space).is_empty() || query_for(&queries, metadata, space).is_some());
+    if !mismatched.is_empty() {
+        println!("Skipped {} files with embeddings from other models, pass --all-models to search them too", mismatched.len());
+    }
 
-    // Generate embedding for the description
-    // TODO: check if models are the same
-    let (_model, embedding_result) = embedding::generate_embedding(&provider, description.to_string()).await;
-    let description_embedding = embedding_result?;
+    // Keyword scores are scaled to 0-1 so the weight means the same whatever the library
+    let mut terms = query::tokenize(&query.text);
+    terms.sort();
+    terms.dedup();
+    let documents: Vec<Vec<String>> = files_metadata.iter().map(|(_file, metadata)| query::tokenize(&metadata.description)).collect();
+    let keyword_scores = query::bm25_scores(&terms, &documents);
+    let max_keyword_score = keyword_scores.iter().cloned().fold(0.0, f64::max);
 
-    // Now generate similarity list
+    // Combine keyword and semantic scores, files not embedded yet only have their keywords
     let mut similarity_list: Vec<(String, f64)> = vec![];
-    for (file, metadata) in files_metadata {
-        let similarity = embedding::cosine_similarity(&metadata.description_embedding, &description_embedding);
-        similarity_list.push((file, similarity));
+    for ((file, metadata), keyword_score) in files_metadata.into_iter().zip(keyword_scores) {
+        let keyword_score = if max_keyword_score > 0.0 { keyword_score / max_keyword_score } else { 0.0 };
+        let semantic_score = query_for(&queries, &metadata, space)
+            .map(|(query, vector)| embedding::cosine_similarity(query, vector))
+            .unwrap_or(0.0);
+        similarity_list.push((file, keyword_weight * keyword_score + (1.0 - keyword_weight) * semantic_score));
     }
-    // Sort by similarity
+    // Sort by score
     similarity_list.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
     if similarity_list.len() > top as usize {
         similarity_list.truncate(top as usize);
@@ -305,30 +688,438 @@ async fn find(provider: &str, files: Vec<String>, description: &str, top: u32) -
     Ok(())
 }
 
-pub async fn run(args: &args::Args) -> Result<(), Box<dyn Error>> {
-    // expand glob pattern in files
-    let files: Vec<String> = glob(&args.files)?
+// Embed the images themselves with the provider's multimodal model
+async fn embed_images(provider: &dyn Provider, store: &MetadataStore, files: Vec<String>, overwrite: bool, max_image_size: u32) -> Result<(), Box<dyn Error>> {
+    let total = files.len();
+    let mut count = 0;
+    for file in files {
+        count += 1;
+        println!("{} / {}: {}", count, total, file);
+
+        let mut metadata = match metadata::get_metadata(store, &file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", file, e);
+                continue;
+            }
+        };
+        if metadata.image_embeddings.contains_key(provider.image_embedding_model()) && !overwrite {
+            println!("Image embedding from {} already exists for {}", provider.image_embedding_model(), file);
+            continue;
+        }
+
+        let (image_embedding_model, embedding_result) = embedding::generate_image_embedding(provider, &file, max_image_size).await;
+        match embedding_result {
+            Ok(embedding) => {
+                metadata.image_embeddings.insert(image_embedding_model, Embedding::new(embedding));
+            }
+            Err(e) => {
+                println!("Failed to generate image embedding for {}: {:?}", file, e);
+                continue;
+            }
+        }
+
+        match metadata::write_metadata(store, &file, metadata).await {
+            Ok(_) => println!("Embedded image {}", file),
+            Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
+        }
+    }
+    Ok(())
+}
+
+// Embed descriptions with the provider's embedding model, next to the embeddings from other models
+async fn re_embed(provider: &dyn Provider, store: &MetadataStore, files: Vec<String>, force: bool) -> Result<(), Box<dyn Error>> {
+    let total = files.len();
+    let mut count = 0;
+    for file in files {
+        count += 1;
+        println!("{} / {}: {}", count, total, file);
+
+        let mut metadata = match metadata::get_metadata(store, &file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", file, e);
+                continue;
+            }
+        };
+        if metadata.description.is_empty() {
+            println!("No description to embed for {}", file);
+            continue;
+        }
+        if metadata.description_embeddings.contains_key(provider.embedding_model()) && !force {
+            println!("Already embedded with {} for {}", provider.embedding_model(), file);
+            continue;
+        }
+
+        let (description_embedding_model, embedding_result) = embedding::generate_embedding(provider, metadata.description.clone()).await;
+        match embedding_result {
+            Ok(embedding) => {
+                metadata.description_embeddings.insert(description_embedding_model, Embedding::new(embedding));
+            }
+            Err(e) => {
+                println!("Failed to generate embedding for {}: {:?}", file, e);
+                continue;
+            }
+        }
+
+        match metadata::write_metadata(store, &file, metadata).await {
+            Ok(_) => println!("Re-embedded description for {}", file),
+            Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
+        }
+    }
+    Ok(())
+}
+
+// Move metadata embedded in EXIF into xmp sidecars, leaving the images without it
+async fn migrate_to_sidecar(store: &MetadataStore, files: Vec<String>, overwrite: bool) -> Result<(), Box<dyn Error>> {
+    for file in files {
+        let metadata = match metadata::get_exif_metadata(&file) {
+            Ok(Some(metadata)) => metadata,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", file, e);
+                continue;
+            }
+        };
+
+        if xmp::sidecar_path(&file).exists() && !overwrite {
+            println!("Sidecar already exists for {}", file);
+            continue;
+        }
+        let original_description = metadata.original_description.clone();
+        let sidecar_store = MetadataStore { storage: Storage::Xmp, ..*store };
+        if let Err(e) = metadata::write_metadata(&sidecar_store, &file, metadata).await {
+            println!("Failed to write sidecar for {}: {:?}", file, e);
+            continue;
+        }
+        // Only strip the image once the sidecar is safely written, giving back any original caption
+        match metadata::restore_exif_description(&file, &original_description) {
+            Ok(_) => println!("Moved metadata for {} to {}", file, xmp::sidecar_path(&file).display()),
+            Err(e) => println!("Failed to remove embedded metadata for {}: {:?}", file, e),
+        }
+    }
+    Ok(())
+}
+
+// Rewrite metadata stored in an older schema in the current one
+async fn migrate(store: &MetadataStore, files: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut migrated = 0;
+    for file in files {
+        let metadata = match metadata::get_metadata(store, &file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Failed to get metadata for {}: {:?}", file, e);
+                continue;
+            }
+        };
+        if !metadata.needs_migration() {
+            continue;
+        }
+
+        let version = metadata.stored_schema_version.unwrap_or_default();
+        match metadata::write_metadata(store, &file, metadata).await {
+            Ok(_) => {
+                migrated += 1;
+                println!("Migrated {} from schema version {} to {}", file, version, metadata::SCHEMA_VERSION);
+            }
+            Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
+        }
+    }
+    println!("Migrated {} files", migrated);
+    Ok(())
+}
+
+// Build the catalog, or bring it up to date with the files
+async fn index(catalog: &Catalog, store: &MetadataStore, files: Vec<String>, rebuild: bool) -> Result<(), Box<dyn Error>> {
+    let stats = catalog.refresh(store, &files, rebuild)?;
+    println!(
+        "Indexed {} new and {} changed files, {} unchanged, removed {} missing files",
+        stats.added, stats.updated, stats.unchanged, stats.removed
+    );
+    Ok(())
+}
+
+// Number of descriptions closest to the centre of a cluster the LLM names it from
+const CLUSTER_NAME_SAMPLES: usize = 10;
+
+// Model most of the files were embedded with in a space, the one a library is usually on
+fn most_common_model(files_metadata: &[(String, metadata::PhotoMeta)], space: EmbeddingSpace) -> Option<String> {
+    let mut counts: BTreeMap<&String, usize> = BTreeMap::new();
+    for (_file, metadata) in files_metadata {
+        for model in metadata.embeddings(space).keys() {
+            if metadata.embedding(space, model).is_some() {
+                *counts.entry(model).or_default() += 1;
+            }
+        }
+    }
+    counts.into_iter().max_by_key(|(_model, count)| *count).map(|(model, _count)| model.clone())
+}
+
+// Group the files into albums with k-means on the embeddings of the configured model, or the one most
+// files were embedded with. Albums are named by the LLM when there is a provider, numbered otherwise.
+async fn cluster(provider: Option<&dyn Provider>, catalog: Option<&Catalog>, store: &MetadataStore, provider_config: &ProviderConfig, files: Vec<String>, clusters: Option<usize>, space: EmbeddingSpace, as_tag: bool) -> Result<(), Box<dyn Error>> {
+    let files_metadata = catalog::get_metadata_list(catalog, store, &files)?;
+    let configured_model = match space {
+        EmbeddingSpace::Description => provider_config.embedding_model.clone(),
+        EmbeddingSpace::Image => provider_config.image_embedding_model.clone(),
+    };
+    let model = match configured_model.or_else(|| most_common_model(&files_metadata, space)) {
+        Some(model) => model,
+        None => {
+            println!("No files with a {} embedding to cluster", space.name());
+            return Ok(());
+        }
+    };
+    let model = model.as_str();
+    let (files_metadata, skipped): (Vec<_>, Vec<_>) = files_metadata
+        .into_iter()
+        .partition(|(_file, metadata)| metadata.embedding(space, model).is_some());
+    if !skipped.is_empty() {
+        println!("Skipped {} files without a {} embedding from {}", skipped.len(), space.name(), model);
+    }
+    if files_metadata.is_empty() {
+        println!("No files to cluster");
+        return Ok(());
+    }
+
+    let vectors: Vec<Vec<f64>> = files_metadata
+        .iter()
+        .map(|(_file, metadata)| metadata.embedding(space, model).unwrap_or_default().to_vec())
+        .collect();
+    let k = clusters.unwrap_or_else(|| cluster::default_cluster_count(vectors.len()));
+    let clustering = cluster::kmeans(&vectors, k);
+
+    let mut files_metadata: Vec<Option<(String, metadata::PhotoMeta)>> = files_metadata.into_iter().map(Some).collect();
+    for cluster_id in 0..clustering.centroids.len() {
+        let members = clustering.members(&vectors, cluster_id);
+        if members.is_empty() {
+            continue;
+        }
+
+        // Named from the most typical images, falling back to the number when they have no descriptions
+        let descriptions: Vec<&str> = members
+            .iter()
+            .filter_map(|i| files_metadata[*i].as_ref())
+            .map(|(_file, metadata)| metadata.description.as_str())
+            .filter(|description| !description.is_empty())
+            .take(CLUSTER_NAME_SAMPLES)
+            .collect();
+        let mut name = String::new();
+        if let Some(provider) = provider.filter(|_| !descriptions.is_empty()) {
+            match llm::name_cluster(provider, &descriptions).await {
+                Ok(cluster_name) => name = cluster_name,
+                Err(e) => println!("Failed to name cluster {}: {:?}", cluster_id, e),
+            }
+        }
+        if name.is_empty() {
+            name = format!("Cluster {}", cluster_id);
+        }
+        println!("Cluster {}: {} ({} images)", cluster_id, name, members.len());
+
+        for i in members {
+            let (file, mut metadata) = match files_metadata[i].take() {
+                Some(file_metadata) => file_metadata,
+                None => continue,
+            };
+            if as_tag {
+                // A tag from an earlier run is replaced with the new name
+                let previous_name = metadata.cluster_name.clone();
+                metadata.tags.retain(|tag| *tag != previous_name);
+                if !metadata.tags.contains(&name) && !metadata.rejects_tag(&name) {
+                    metadata.tags.push(name.clone());
+                }
+            }
+            metadata.cluster_id = Some(cluster_id as u32);
+            metadata.cluster_name = name.clone();
+            match metadata::write_metadata(store, &file, metadata).await {
+                Ok(_) => println!("  {}", file),
+                Err(e) => println!("Failed to write metadata for {}: {:?}", file, e),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Put a reject next to the others in the rejects directory
+fn move_reject(file: &str, rejects_directory: &str) -> Result<std::path::PathBuf, Box<dyn Error>> {
+    let new_file = std::path::Path::new(rejects_directory).join(std::path::Path::new(file).file_name().ok_or("No file name")?);
+    if new_file.exists() {
+        return Err(format!("{} already exists", new_file.display()).into());
+    }
+    move_with_sidecar(file, &new_file)?;
+    Ok(new_file)
+}
+
+// Swap a reject for a hard link to the keeper, the link is made next to it first so the reject is
+// only replaced once linking worked. Only exact copies are linked, false when the reject differs
+// from the keeper, a near-duplicate would be lost.
+fn link_reject(file: &str, keeper: &str) -> Result<bool, Box<dyn Error>> {
+    if !util::same_content(file, keeper)? {
+        return Ok(false);
+    }
+    let link = format!("{}.glimpse-link", file);
+    std::fs::hard_link(keeper, &link)?;
+    if let Err(e) = std::fs::rename(&link, file) {
+        let _ = std::fs::remove_file(&link);
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+// Group images whose perceptual hashes are within the threshold
+async fn find_duplicates(files: Vec<String>, hash: ImageHash, threshold: u32, move_rejects: Option<&str>, hard_link: bool) -> Result<(), Box<dyn Error>> {
+    let total = files.len();
+    let mut hashes: Vec<(String, u64)> = vec![];
+    for (count, file) in files.into_iter().enumerate() {
+        println!("{} / {}: {}", count + 1, total, file);
+        match images::image_hash(&file, hash) {
+            Ok(value) => hashes.push((file, value)),
+            Err(e) => println!("Failed to hash {}: {:?}", file, e),
+        }
+    }
+
+    let values: Vec<u64> = hashes.iter().map(|(_file, value)| *value).collect();
+    let groups = cluster::connected_groups(hashes.len(), cluster::hamming_links(&values, threshold));
+
+    if let Some(rejects_directory) = move_rejects {
+        std::fs::create_dir_all(rejects_directory)?;
+    }
+    let mut duplicates = 0;
+    for (number, members) in groups.iter().filter(|members| members.len() > 1).enumerate() {
+        // The keeper has the most pixels, then the sharpest, which picks the best shot of a burst
+        let mut ranked: Vec<(&str, (u32, u32), f64)> = vec![];
+        for &i in members {
+            let file = hashes[i].0.as_str();
+            let dimensions = images::dimensions(file).unwrap_or_default();
+            let sharpness = images::sharpness(file).unwrap_or_default();
+            ranked.push((file, dimensions, sharpness));
+        }
+        ranked.sort_by(|a, b| {
+            let pixels = |dimensions: (u32, u32)| dimensions.0 as u64 * dimensions.1 as u64;
+            pixels(b.1).cmp(&pixels(a.1)).then(b.2.total_cmp(&a.2))
+        });
+
+        println!("Group {}:", number + 1);
+        let keeper = ranked[0].0;
+        for (i, (file, (width, height), sharpness)) in ranked.iter().enumerate() {
+            println!("  {} {} ({}x{}, sharpness {:.1})", if i == 0 { "keep  " } else { "reject" }, file, width, height, sharpness);
+        }
+        for (file, _dimensions, _sharpness) in &ranked[1..] {
+            duplicates += 1;
+            if let Some(rejects_directory) = move_rejects {
+                match move_reject(file, rejects_directory) {
+                    Ok(new_file) => println!("Moved {} to {}", file, new_file.display()),
+                    Err(e) => println!("Failed to move {}: {:?}", file, e),
+                }
+            } else if hard_link {
+                match link_reject(file, keeper) {
+                    Ok(true) => println!("Linked {} to {}", file, keeper),
+                    Ok(false) => println!("Kept {}, it isn't an exact copy of {}", file, keeper),
+                    Err(e) => println!("Failed to link {} to {}: {:?}", file, keeper, e),
+                }
+            }
+        }
+    }
+    println!("Found {} duplicates", duplicates);
+    Ok(())
+}
+
+// Capabilities each action needs from the provider
+fn required_capabilities(action: &Action) -> Vec<Capability> {
+    match action {
+        Action::TagPerson { .. } => vec![Capability::CompareFaces],
+        Action::EnrollPerson { .. } | Action::TagPeople { .. } => vec![Capability::FaceCollection],
+        Action::DiscoverFaces { .. } => vec![Capability::DiscoverFaces],
+        Action::TagDescription { .. } => vec![Capability::DescribeImage, Capability::Embed],
+        Action::Tag { .. } | Action::Cluster { no_names: false, .. } => vec![Capability::Complete],
+        Action::Find { space: EmbeddingSpace::Image, .. } | Action::EmbedImages { .. } => vec![Capability::ImageEmbed],
+        Action::Find { .. } | Action::ReEmbed { .. } => vec![Capability::Embed],
+        Action::FindPerson { .. }
+        | Action::UntagPerson { .. }
+        | Action::Untag { .. }
+        | Action::ClearMetadata { .. }
+        | Action::SortByTag { .. }
+        | Action::FindSimilar { .. }
+        | Action::ShowMetadata { .. }
+        | Action::MigrateToSidecar { .. }
+        | Action::Index { .. }
+        | Action::FindDuplicates { .. }
+        | Action::NameCluster { .. }
+        | Action::Cluster { no_names: true, .. }
+        | Action::Migrate { .. } => vec![],
+    }
+}
+
+// expand glob pattern in files
+fn expand_files(pattern: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let files = glob(pattern)?
         .filter_map(Result::ok)  // Handle errors for individual paths
         .filter_map(|path| path.to_str().map(String::from))  // Convert to strings
         .collect();
+    Ok(files)
+}
 
-    let tags: Vec<String> = args.tags.split(',')
-        .map(|tag| tag.trim().to_string())  // Split and trim whitespace
-        .collect();
+pub async fn run(args: &Args) -> Result<(), Box<dyn Error>> {
+    // Command line overrides the library config, which overrides the user config
+    let config = config::load(args.action.files())?.merge(config::from_args(args));
+
+    // Only actions that need a provider build one, and fail fast if it can't perform the action
+    let capabilities = required_capabilities(&args.action);
+    let loaded = if capabilities.is_empty() {
+        None
+    } else {
+        let provider = provider::get_provider(&config.provider_config()).await?;
+        provider.require(&capabilities)?;
+        Some(provider)
+    };
+    let provider = || loaded.as_deref().ok_or("No provider was built for this action");
+
+    let store = &config.metadata_store();
+    // Searches use the catalog when one has been built, and read the files otherwise
+    let catalog_path = config.catalog_path(args.action.files());
+    let catalog = if catalog_path.is_file() { Some(Catalog::open(&catalog_path)?) } else { None };
+    let catalog = catalog.as_ref();
 
-    match args.action.as_str() {
-        "tag-person" => tag_person(&args.provider, &args.reference_file, files, &args.person_name, args.confidence).await,
-        "find-person" => find_person(&args.provider, files, &args.person_name).await,
-        "tag-description" => tag_description(&args.provider, files, args.overwrite, &args.prompt).await,
-        "tag" => tag(&args.provider, files, &tags, args.overwrite).await,
-        "clear-metadata" => clear_metadata(files).await,
-        "sort-by-tag" => sort_by_tag(files, &args.output_directory).await,
-        "find-similar" => find_similar(&args.reference_file, files, args.top).await,
-        "find" => find(&args.provider, files, &args.description, args.top).await,
-        "show-metadata" => show_metadata(files).await,
-        _ => {
-            println!("Unknown action: {}", args.action);
-            Ok(())
-        }
-    }
-}
\ No newline at end of file
+    match &args.action {
+        Action::TagPerson { files, person_name, reference_file, face_index, .. } => tag_person(provider()?, store, reference_file, *face_index, expand_files(files)?, person_name, config.confidence()).await,
+        Action::UntagPerson { files, person_name } => untag_person(store, expand_files(files)?, person_name).await,
+        Action::EnrollPerson { files, person_name, .. } => enroll_person(provider()?, &config.face_collection(), expand_files(files)?, person_name).await,
+        Action::TagPeople { files, .. } => tag_people(provider()?, store, expand_files(files)?, &config.face_collection(), config.confidence()).await,
+        Action::DiscoverFaces { files, min_faces, .. } => {
+            let output_directory = config.faces_directory()?;
+            discover_faces(provider()?, expand_files(files)?, &output_directory, config.confidence(), *min_faces).await
+        }
+        Action::NameCluster { cluster, person_name, .. } => name_cluster(store, &config.faces_directory()?, *cluster, person_name).await,
+        Action::FindPerson { files, person_name } => find_person(catalog, store, expand_files(files)?, person_name).await,
+        Action::TagDescription { files, overwrite, prompt_name, .. } => {
+            let prompt = config.prompt(prompt_name.as_deref())?;
+            tag_description(provider()?, store, expand_files(files)?, *overwrite, &prompt, config.max_image_size()).await
+        }
+        Action::Tag { files, tags, overwrite } => {
+            let tags: Vec<String> = tags.iter().map(|tag| tag.trim().to_string()).collect();
+            tag(provider()?, store, expand_files(files)?, &tags, *overwrite).await
+        }
+        Action::Untag { files, tags } => {
+            let tags: Vec<String> = tags.iter().map(|tag| tag.trim().to_string()).collect();
+            untag(store, expand_files(files)?, &tags).await
+        }
+        Action::ClearMetadata { files } => clear_metadata(store, expand_files(files)?).await,
+        Action::SortByTag { files, .. } => {
+            let output_directory = config.sort_directory()?;
+            sort_by_tag(store, expand_files(files)?, &output_directory).await
+        }
+        Action::FindSimilar { files, reference_file, space, all_models, .. } => find_similar(catalog, store, &config.provider_config(), reference_file, expand_files(files)?, config.top(), *space, *all_models).await,
+        Action::Find { files, query, space, all_models, .. } => find(provider()?, catalog, store, &config.provider_config(), expand_files(files)?, query, config.top(), config.keyword_weight(), *space, *all_models).await,
+        Action::EmbedImages { files, overwrite } => embed_images(provider()?, store, expand_files(files)?, *overwrite, config.max_image_size()).await,
+        Action::ReEmbed { files, force } => re_embed(provider()?, store, expand_files(files)?, *force).await,
+        Action::ShowMetadata { files } => show_metadata(catalog, store, expand_files(files)?).await,
+        Action::MigrateToSidecar { files, overwrite } => migrate_to_sidecar(store, expand_files(files)?, *overwrite).await,
+        Action::Index { files, rebuild } => index(&Catalog::open(&catalog_path)?, store, expand_files(files)?, *rebuild).await,
+        Action::Cluster { files, clusters, space, as_tag, no_names } => {
+            let provider = (!*no_names).then(provider).transpose()?;
+            cluster(provider, catalog, store, &config.provider_config(), expand_files(files)?, *clusters, *space, *as_tag).await
+        }
+        Action::FindDuplicates { files, hash, threshold, move_rejects, hard_link } => find_duplicates(expand_files(files)?, *hash, *threshold, move_rejects.as_deref(), *hard_link).await,
+        Action::Migrate { files } => migrate(store, expand_files(files)?).await,
+    }
+}
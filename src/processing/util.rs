@@ -0,0 +1,53 @@
+use std::error::Error;
+
+// FNV-1a, stable across platforms and rust versions unlike DefaultHasher
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Whether two files hold the same bytes, compared in full as two different files can share a hash
+pub fn same_content(a: &str, b: &str) -> Result<bool, Box<dyn Error>> {
+    if std::fs::metadata(a)?.len() != std::fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    Ok(std::fs::read(a)? == std::fs::read(b)?)
+}
+
+// Name made of [a-zA-Z0-9_.\-:] only, other bytes are written as :XX. Safe as a Rekognition
+// ExternalImageId and as a file name.
+pub fn encode_name(name: &str) -> String {
+    name
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' | b'-' => (byte as char).to_string(),
+            _ => format!(":{:02X}", byte),
+        })
+        .collect()
+}
+
+pub fn decode_name(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b':')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
@@ -0,0 +1,544 @@
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::graphics::images;
+use crate::processing::metadata::{self, BoundingBox, FaceRegion, FaceSource, PhotoMeta};
+
+// Element holding the serialized PhotoMeta
+const PHOTO_META_ELEMENT: &[u8] = b"glimpse:PhotoMeta";
+// Standard properties understood by Lightroom, digiKam, darktable, ...
+const SUBJECT_ELEMENT: &[u8] = b"dc:subject";
+const DESCRIPTION_ELEMENT: &[u8] = b"dc:description";
+const PERSON_IN_IMAGE_ELEMENT: &[u8] = b"Iptc4xmpExt:PersonInImage";
+// Metadata Working Group face rectangles
+const REGIONS_ELEMENT: &[u8] = b"mwg-rs:Regions";
+const REGION_LIST_ELEMENT: &[u8] = b"mwg-rs:RegionList";
+// Marks the regions glimpse wrote, the only ones it replaces or removes
+const OWNED_REGION_ELEMENT: &[u8] = b"glimpse:Owned";
+const PROPERTY_ELEMENTS: [&[u8]; 5] = [PHOTO_META_ELEMENT, SUBJECT_ELEMENT, DESCRIPTION_ELEMENT, PERSON_IN_IMAGE_ELEMENT, REGIONS_ELEMENT];
+
+const NAMESPACES: [(&str, &str); 6] = [
+    ("glimpse", "https://github.com/feathj/glimpse/ns/1.0/"),
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    ("Iptc4xmpExt", "http://iptc.org/std/Iptc4xmpExt/2008-02-29/"),
+    ("mwg-rs", "http://www.metadataworkinggroup.com/schemas/regions/"),
+    ("stArea", "http://ns.adobe.com/xmp/sType/Area#"),
+    ("stDim", "http://ns.adobe.com/xap/1.0/sType/Dimensions#"),
+];
+
+// Everything glimpse understands in a sidecar
+#[derive(Debug, Default)]
+pub struct Sidecar {
+    pub photo_meta: Option<PhotoMeta>,
+    pub subjects: Vec<String>,
+    pub people: Vec<String>,
+    pub description: Option<String>,
+    pub faces: Vec<FaceRegion>,
+}
+
+// Region of mwg-rs:RegionList being read. Tools write its fields either as attributes or as child
+// elements, and the area by its centre.
+#[derive(Default)]
+struct RegionItem {
+    name: String,
+    kind: String,
+    area: BoundingBox,
+    owned: bool,
+}
+
+impl RegionItem {
+    fn read_field(&mut self, field: &[u8], value: String) {
+        let number = || value.parse::<f32>().unwrap_or_default();
+        match field {
+            b"mwg-rs:Name" => self.name = value.trim().to_string(),
+            b"mwg-rs:Type" => self.kind = value.trim().to_string(),
+            b"stArea:x" => self.area.left = number(),
+            b"stArea:y" => self.area.top = number(),
+            b"stArea:w" => self.area.width = number(),
+            b"stArea:h" => self.area.height = number(),
+            OWNED_REGION_ELEMENT => self.owned = value.trim() == "True",
+            _ => {}
+        }
+    }
+
+    fn read_attributes(&mut self, element: &BytesStart) {
+        for attribute in element.attributes().filter_map(Result::ok) {
+            if let Ok(value) = attribute.unescape_value() {
+                self.read_field(attribute.key.as_ref(), value.to_string());
+            }
+        }
+    }
+
+    // Named faces only, other regions (pets, focus areas, ...) aren't people. Regions glimpse wrote
+    // mirror its own faces.
+    fn into_face(self) -> Option<FaceRegion> {
+        if self.owned || self.name.is_empty() || !(self.kind.is_empty() || self.kind == "Face") {
+            return None;
+        }
+        let bounding_box = BoundingBox {
+            left: self.area.left - self.area.width / 2.0,
+            top: self.area.top - self.area.height / 2.0,
+            width: self.area.width,
+            height: self.area.height,
+        };
+        Some(FaceRegion { person: self.name, bounding_box, similarity: 100.0, source: FaceSource::Manual, ..FaceRegion::default() })
+    }
+}
+
+impl Sidecar {
+    // Add standard fields written by glimpse or other tools to the metadata
+    pub fn merge_into(&self, metadata: &mut PhotoMeta) {
+        // People and tags removed in glimpse stay removed, even when another tool still lists them
+        for subject in &self.subjects {
+            if !metadata.tags.contains(subject) && !metadata.rejects_tag(subject) {
+                metadata.tags.push(subject.clone());
+            }
+        }
+        for person in &self.people {
+            if !metadata.people.contains(person) && !metadata.rejects_person(person) {
+                metadata.people.push(person.clone());
+            }
+        }
+        if let Some(description) = &self.description {
+            if metadata.description.is_empty() {
+                metadata.description = description.clone();
+            }
+        }
+        for face in &self.faces {
+            if metadata.rejects_person(&face.person) {
+                continue;
+            }
+            if !metadata.faces.iter().any(|region| region.person == face.person) {
+                metadata.faces.push(face.clone());
+            }
+            if !metadata.people.contains(&face.person) {
+                metadata.people.push(face.person.clone());
+            }
+        }
+    }
+}
+
+// What write_sidecar puts in the sidecar
+#[derive(Debug, Clone, Copy)]
+pub struct SidecarContent {
+    // The full PhotoMeta as JSON
+    pub photo_meta: bool,
+    // Tags, people and description as dc:subject, Iptc4xmpExt:PersonInImage and dc:description,
+    // and face regions as mwg-rs:Regions
+    pub standard_fields: bool,
+}
+
+impl SidecarContent {
+    fn manages(&self, element: &[u8]) -> bool {
+        (self.photo_meta && element == PHOTO_META_ELEMENT)
+            || (self.standard_fields
+                && [SUBJECT_ELEMENT, DESCRIPTION_ELEMENT, PERSON_IN_IMAGE_ELEMENT].contains(&element))
+    }
+}
+
+// IMG_0001.jpg -> IMG_0001.jpg.xmp, the convention of darktable and digiKam. Keeping the extension
+// gives each file of a RAW+JPEG pair its own sidecar.
+pub fn sidecar_path(file: &str) -> PathBuf {
+    let mut path = Path::new(file).as_os_str().to_owned();
+    path.push(".xmp");
+    PathBuf::from(path)
+}
+
+pub fn read_sidecar(file: &str) -> Result<Option<Sidecar>, Box<dyn Error>> {
+    let path = sidecar_path(file);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(&path)?;
+
+    let mut reader = Reader::from_str(&contents);
+    reader.config_mut().trim_text(true);
+    let mut sidecar = Sidecar::default();
+    // Property currently being read, and whether we are inside one of its rdf:li items
+    let mut property: Vec<u8> = vec![];
+    let mut in_item = false;
+    // Region being read and the element whose text is read into it
+    let mut region: Option<RegionItem> = None;
+    let mut field: Vec<u8> = vec![];
+    loop {
+        let event = reader.read_event()?;
+        if property == REGIONS_ELEMENT {
+            match &event {
+                Event::Start(e) | Event::Empty(e) => {
+                    if e.name().as_ref() == b"rdf:li" {
+                        region = Some(RegionItem::default());
+                    }
+                    if let Some(region) = region.as_mut() {
+                        region.read_attributes(e);
+                    }
+                    field = e.name().as_ref().to_vec();
+                }
+                Event::Text(text) => {
+                    if let Some(region) = region.as_mut() {
+                        region.read_field(&field, text.unescape()?.to_string());
+                    }
+                }
+                _ => {}
+            }
+            let region_ended = match &event {
+                Event::End(e) => e.name().as_ref() == b"rdf:li",
+                Event::Empty(e) => e.name().as_ref() == b"rdf:li",
+                _ => false,
+            };
+            if region_ended {
+                if let Some(face) = region.take().and_then(RegionItem::into_face) {
+                    sidecar.faces.push(face);
+                }
+            }
+        }
+        match event {
+            Event::Start(e) if e.name().as_ref() == b"rdf:li" => in_item = true,
+            Event::Start(e) if PROPERTY_ELEMENTS.contains(&e.name().as_ref()) => property = e.name().as_ref().to_vec(),
+            Event::Text(text) if !property.is_empty() => {
+                let text = text.unescape()?.to_string();
+                match property.as_slice() {
+                    PHOTO_META_ELEMENT => sidecar.photo_meta = metadata::parse_metadata(&text),
+                    SUBJECT_ELEMENT if in_item => sidecar.subjects.push(text),
+                    PERSON_IN_IMAGE_ELEMENT if in_item => sidecar.people.push(text),
+                    DESCRIPTION_ELEMENT if in_item => {
+                        // First language alternative, x-default by convention
+                        sidecar.description.get_or_insert(text);
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(e) if e.name().as_ref() == b"rdf:li" => in_item = false,
+            Event::End(e) if e.name().as_ref() == property => property.clear(),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(Some(sidecar))
+}
+
+fn bag(element: &str, items: &[String]) -> String {
+    let items = items
+        .iter()
+        .map(|item| format!("     <rdf:li>{}</rdf:li>\n", escape(item.as_str())))
+        .collect::<String>();
+    format!("   <{element}>\n    <rdf:Bag>\n{items}    </rdf:Bag>\n   </{element}>\n")
+}
+
+// MWG regions give the centre of the area, normalized to the image
+fn region_items(faces: &[FaceRegion]) -> String {
+    faces
+        .iter()
+        .map(|face| {
+            let area = face.bounding_box;
+            format!(
+                "      <rdf:li rdf:parseType=\"Resource\">\n       <mwg-rs:Name>{}</mwg-rs:Name>\n       <mwg-rs:Type>Face</mwg-rs:Type>\n       <mwg-rs:Area stArea:x=\"{}\" stArea:y=\"{}\" stArea:w=\"{}\" stArea:h=\"{}\" stArea:unit=\"normalized\"/>\n       <glimpse:Owned>True</glimpse:Owned>\n      </rdf:li>\n",
+                escape(face.person.as_str()),
+                area.left + area.width / 2.0,
+                area.top + area.height / 2.0,
+                area.width,
+                area.height,
+            )
+        })
+        .collect()
+}
+
+fn region_list(items: &str) -> String {
+    format!("    <mwg-rs:RegionList>\n     <rdf:Bag>\n{items}     </rdf:Bag>\n    </mwg-rs:RegionList>\n")
+}
+
+fn regions(items: &str, dimensions: Option<(u32, u32)>) -> String {
+    let applied_to_dimensions = dimensions
+        .map(|(width, height)| format!("    <mwg-rs:AppliedToDimensions stDim:w=\"{}\" stDim:h=\"{}\" stDim:unit=\"pixel\"/>\n", width, height))
+        .unwrap_or_default();
+    format!("   <mwg-rs:Regions rdf:parseType=\"Resource\">\n{applied_to_dimensions}{}   </mwg-rs:Regions>\n", region_list(items))
+}
+
+// Property elements for the rdf:Description, face regions are written separately
+fn properties(photo_metadata: &PhotoMeta, content: SidecarContent) -> Result<String, Box<dyn Error>> {
+    let mut xml = String::new();
+    if content.photo_meta {
+        let json = serde_json::to_string(photo_metadata)?;
+        xml.push_str(&format!("   <glimpse:PhotoMeta>{}</glimpse:PhotoMeta>\n", escape(json.as_str())));
+    }
+    if content.standard_fields {
+        xml.push_str(&bag("dc:subject", &photo_metadata.tags));
+        xml.push_str(&bag("Iptc4xmpExt:PersonInImage", &photo_metadata.people));
+        if !photo_metadata.description.is_empty() {
+            xml.push_str(&format!(
+                "   <dc:description>\n    <rdf:Alt>\n     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n    </rdf:Alt>\n   </dc:description>\n",
+                escape(photo_metadata.description.as_str())
+            ));
+        }
+    }
+    Ok(xml)
+}
+
+fn new_sidecar(properties: &str) -> String {
+    let namespaces = NAMESPACES
+        .iter()
+        .map(|(prefix, uri)| format!("\n    xmlns:{}=\"{}\"", prefix, uri))
+        .collect::<String>();
+    format!(
+        r#"<?xpacket begin="{bom}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""{namespaces}>
+{properties}  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+        bom = '\u{feff}',
+    )
+}
+
+// rdf:Description start tag with any of our namespace declarations it is missing
+fn with_namespaces<'a>(description: &BytesStart<'a>) -> BytesStart<'a> {
+    let mut description = description.clone();
+    for (prefix, uri) in NAMESPACES {
+        let key = format!("xmlns:{}", prefix);
+        let declared = description
+            .attributes()
+            .filter_map(Result::ok)
+            .any(|attribute| attribute.key.as_ref() == key.as_bytes());
+        if !declared {
+            description.push_attribute((key.as_str(), uri));
+        }
+    }
+    description
+}
+
+fn has_element(xml: &str, name: &[u8]) -> Result<bool, Box<dyn Error>> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.name().as_ref() == name => return Ok(true),
+            Event::Eof => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+fn is_whitespace(event: &Event) -> bool {
+    matches!(event, Event::Text(text) if text.iter().all(u8::is_ascii_whitespace))
+}
+
+// Elements written as lines of their own, before the indentation of the tag that follows
+fn write_lines(writer: &mut Writer<Vec<u8>>, xml: &str) {
+    if !xml.is_empty() {
+        writer.get_mut().extend_from_slice(format!("\n{}", xml.trim_end()).as_bytes());
+    }
+}
+
+// mwg-rs:Regions with the regions glimpse wrote replaced by items. Regions of other tools and the
+// rest of the element are kept, None when no regions are left.
+fn rewrite_regions(events: Vec<Event<'static>>, items: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut writer = Writer::new(Vec::new());
+    let mut kept = 0;
+    let mut in_list = false;
+    let mut listed = false;
+    // Whitespace before the next element, dropped with it when the element is
+    let mut indentation: Option<Event<'static>> = None;
+    // Events of the region being read, whether glimpse wrote it and the depth inside it
+    let mut region: Option<(Vec<Event<'static>>, bool, usize)> = None;
+    for event in events {
+        if let Some((region_events, owned, depth)) = region.as_mut() {
+            match &event {
+                Event::Start(e) => {
+                    *depth += 1;
+                    *owned |= e.name().as_ref() == OWNED_REGION_ELEMENT;
+                }
+                Event::Empty(e) => *owned |= e.name().as_ref() == OWNED_REGION_ELEMENT,
+                Event::End(_) => *depth -= 1,
+                _ => {}
+            }
+            region_events.push(event);
+            if *depth == 0 {
+                let (region_events, owned, _depth) = region.take().unwrap_or_default();
+                if !owned {
+                    kept += 1;
+                    for event in region_events {
+                        writer.write_event(event)?;
+                    }
+                }
+            }
+            continue;
+        }
+        if is_whitespace(&event) {
+            if let Some(previous) = indentation.replace(event) {
+                writer.write_event(previous)?;
+            }
+            continue;
+        }
+
+        match &event {
+            Event::Start(e) if in_list && e.name().as_ref() == b"rdf:li" => {
+                let mut region_events: Vec<Event<'static>> = indentation.take().into_iter().collect();
+                region_events.push(event);
+                region = Some((region_events, false, 1));
+                continue;
+            }
+            Event::Empty(e) if in_list && e.name().as_ref() == b"rdf:li" => kept += 1,
+            Event::Start(e) if e.name().as_ref() == REGION_LIST_ELEMENT => {
+                in_list = true;
+                listed = true;
+            }
+            Event::End(e) if e.name().as_ref() == REGION_LIST_ELEMENT => in_list = false,
+            Event::Empty(e) if e.name().as_ref() == REGION_LIST_ELEMENT => {
+                listed = true;
+                if let Some(indentation) = indentation.take() {
+                    writer.write_event(indentation)?;
+                }
+                writer.get_mut().extend_from_slice(region_list(items).trim().as_bytes());
+                continue;
+            }
+            Event::Empty(e) if in_list && e.name().as_ref() == b"rdf:Bag" => {
+                if let Some(indentation) = indentation.take() {
+                    writer.write_event(indentation)?;
+                }
+                writer.get_mut().extend_from_slice(format!("<rdf:Bag>\n{}</rdf:Bag>", items).as_bytes());
+                continue;
+            }
+            Event::End(e) if in_list && e.name().as_ref() == b"rdf:Bag" => write_lines(&mut writer, items),
+            Event::End(e) if !listed && e.name().as_ref() == REGIONS_ELEMENT => write_lines(&mut writer, &region_list(items)),
+            _ => {}
+        }
+        if let Some(indentation) = indentation.take() {
+            writer.write_event(indentation)?;
+        }
+        writer.write_event(event)?;
+    }
+    if let Some(indentation) = indentation {
+        writer.write_event(indentation)?;
+    }
+
+    if kept == 0 && items.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(writer.into_inner()))
+}
+
+// Rewrite an existing sidecar, replacing only the properties glimpse manages so data written by
+// other tools (develop settings, ratings, ...) is kept. Of the regions only the ones glimpse wrote are
+// replaced, regions is the whole element for a sidecar without one.
+fn merge_sidecar(existing: &str, properties: &str, content: SidecarContent, items: &str, regions: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let properties = if content.standard_fields && !has_element(existing, REGIONS_ELEMENT)? {
+        format!("{}{}", properties, regions)
+    } else {
+        properties.to_string()
+    };
+    let mut reader = Reader::from_str(existing);
+    let mut writer = Writer::new(Vec::new());
+    // Depth inside a managed property being dropped
+    let mut skip_depth = 0;
+    let mut inserted = false;
+    // Whitespace before the next element, dropped with it when the element is
+    let mut indentation: Option<Event> = None;
+    loop {
+        let event = reader.read_event()?;
+        if skip_depth == 0 && is_whitespace(&event) {
+            if let Some(previous) = indentation.replace(event) {
+                writer.write_event(previous)?;
+            }
+            continue;
+        }
+        match &event {
+            Event::Eof => break,
+            Event::Start(_) if skip_depth > 0 => {
+                skip_depth += 1;
+                continue;
+            }
+            Event::End(_) if skip_depth > 0 => {
+                skip_depth -= 1;
+                continue;
+            }
+            _ if skip_depth > 0 => continue,
+            Event::Start(e) if content.manages(e.name().as_ref()) => {
+                skip_depth = 1;
+                indentation = None;
+                continue;
+            }
+            Event::Empty(e) if content.manages(e.name().as_ref()) => {
+                indentation = None;
+                continue;
+            }
+            Event::Start(e) | Event::Empty(e) if content.standard_fields && e.name().as_ref() == REGIONS_ELEMENT => {
+                let mut events = vec![Event::Start(e.clone().into_owned())];
+                if matches!(event, Event::Empty(_)) {
+                    events.push(Event::End(BytesEnd::new("mwg-rs:Regions")));
+                } else {
+                    let mut depth = 1;
+                    while depth > 0 {
+                        let event = reader.read_event()?;
+                        match event {
+                            Event::Start(_) => depth += 1,
+                            Event::End(_) => depth -= 1,
+                            Event::Eof => return Err("Unclosed mwg-rs:Regions".into()),
+                            _ => {}
+                        }
+                        events.push(event.into_owned());
+                    }
+                }
+                let indentation = indentation.take();
+                if let Some(regions) = rewrite_regions(events, items)? {
+                    if let Some(indentation) = indentation {
+                        writer.write_event(indentation)?;
+                    }
+                    writer.get_mut().extend_from_slice(&regions);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(indentation) = indentation.take() {
+            writer.write_event(indentation)?;
+        }
+        match &event {
+            Event::Start(e) if !inserted && e.name().as_ref() == b"rdf:Description" => {
+                writer.write_event(Event::Start(with_namespaces(e)))?;
+                write_lines(&mut writer, &properties);
+                inserted = true;
+            }
+            Event::Empty(e) if !inserted && e.name().as_ref() == b"rdf:Description" => {
+                writer.write_event(Event::Start(with_namespaces(e)))?;
+                write_lines(&mut writer, &properties);
+                writer.get_mut().extend_from_slice(b"\n  ");
+                writer.write_event(Event::End(BytesEnd::new("rdf:Description")))?;
+                inserted = true;
+            }
+            _ => writer.write_event(event)?,
+        }
+    }
+
+    if !inserted {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(writer.into_inner())?))
+}
+
+pub fn write_sidecar(file: &str, photo_metadata: &PhotoMeta, content: SidecarContent) -> Result<(), Box<dyn Error>> {
+    let path = sidecar_path(file);
+    let properties = properties(photo_metadata, content)?;
+    // Face regions as rdf:li items, and as a whole mwg-rs:Regions for sidecars without one
+    let (items, regions) = if content.standard_fields && !photo_metadata.faces.is_empty() {
+        let items = region_items(&photo_metadata.faces);
+        let regions = regions(&items, images::dimensions(file).ok());
+        (items, regions)
+    } else {
+        (String::new(), String::new())
+    };
+
+    // A sidecar that can't be understood may hold another tool's data, it is never overwritten
+    let xmp = if path.is_file() {
+        merge_sidecar(&std::fs::read_to_string(&path)?, &properties, content, &items, &regions)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+            .ok_or(format!("{} has no rdf:Description, leaving it alone", path.display()))?
+    } else {
+        new_sidecar(&format!("{}{}", properties, regions))
+    };
+    std::fs::write(path, xmp)?;
+    Ok(())
+}
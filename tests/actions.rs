@@ -0,0 +1,315 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use glimpse::processing::metadata::{self, EmbeddingSpace, FaceSource, MetadataStore, PhotoMeta, Storage};
+use glimpse::processing::xmp;
+use image::{Rgb, RgbImage};
+use tempfile::TempDir;
+
+const EMBEDDING_MODEL: &str = "mock-embedding";
+
+// Fixture images, a base color with a block pattern that sets the average hash the mock derives
+// faces from. The reds only differ in shade, like two shots of a burst, so the mock sees the same
+// face and describes them alike.
+const FIXTURES: [(&str, [u8; 3], u32); 4] = [
+    ("red.jpg", [200, 30, 30], 2),
+    ("red-again.jpg", [205, 35, 35], 2),
+    ("green.jpg", [30, 200, 30], 3),
+    ("blue.jpg", [30, 30, 200], 4),
+];
+
+// A directory of fixture images, run through the glimpse binary with the mock provider and a home
+// directory of its own so no user config is picked up
+struct Library {
+    directory: TempDir,
+    home: TempDir,
+}
+
+impl Library {
+    fn new() -> Library {
+        let library = Library { directory: TempDir::new().unwrap(), home: TempDir::new().unwrap() };
+        for (name, color, pattern) in FIXTURES {
+            let img = RgbImage::from_fn(64, 64, |x, y| {
+                let shade = if (x / 8 + y / 8 * pattern) % 3 == 0 { 20 } else { 0 };
+                Rgb(color.map(|value| value.saturating_add(shade)))
+            });
+            img.save(library.file(name)).unwrap();
+        }
+        library
+    }
+
+    fn file(&self, name: &str) -> String {
+        self.directory.path().join(name).to_string_lossy().to_string()
+    }
+
+    fn files(&self) -> String {
+        self.file("*.jpg")
+    }
+
+    // Stdout of a successful run on every fixture
+    fn glimpse(&self, action: &str, args: &[&str]) -> String {
+        self.glimpse_on(action, &self.files(), args)
+    }
+
+    fn glimpse_on(&self, action: &str, files: &str, args: &[&str]) -> String {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_glimpse"));
+        command
+            .args([action, "--provider", "mock", "--files", files])
+            .args(args)
+            .env("HOME", self.home.path())
+            .env_remove("XDG_CONFIG_HOME");
+        // Models picked in the environment would change what the mock records
+        for (name, _value) in std::env::vars().filter(|(name, _value)| name.starts_with("GLIMPSE_")) {
+            command.env_remove(name);
+        }
+        let output = command.output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        assert!(output.status.success(), "glimpse {} {} {:?} failed\n{}{}", action, files, args, stdout, String::from_utf8_lossy(&output.stderr));
+        stdout
+    }
+
+    fn metadata(&self, name: &str) -> PhotoMeta {
+        metadata::get_metadata(&MetadataStore::default(), &self.file(name)).unwrap()
+    }
+
+    fn describe(&self) {
+        self.glimpse("tag-description", &[]);
+    }
+}
+
+// File names of result lines, in order. find prints the path, find-similar the path and similarity.
+fn results(stdout: &str) -> Vec<String> {
+    stdout
+        .lines()
+        .filter_map(|line| line.split(": ").next())
+        .filter(|path| path.ends_with(".jpg") && Path::new(path).is_absolute())
+        .map(|path| PathBuf::from(path).file_name().unwrap().to_string_lossy().to_string())
+        .collect()
+}
+
+#[test]
+fn tag_description_describes_and_embeds() {
+    let library = Library::new();
+    library.describe();
+
+    for (name, _color, _pattern) in FIXTURES {
+        let metadata = library.metadata(name);
+        assert!(!metadata.description.is_empty(), "{} has no description", name);
+        assert!(metadata.embedding(EmbeddingSpace::Description, EMBEDDING_MODEL).is_some(), "{} has no embedding", name);
+    }
+    assert!(library.metadata("red.jpg").description.contains("mostly red"));
+    assert!(library.metadata("blue.jpg").description.contains("mostly blue"));
+}
+
+#[test]
+fn tag_description_keeps_embeddings_from_other_models() {
+    let library = Library::new();
+    library.describe();
+    library.glimpse_on("tag-description", &library.file("red.jpg"), &["--overwrite", "--embedding-model", "mock-other"]);
+
+    let metadata = library.metadata("red.jpg");
+    assert!(metadata.embedding(EmbeddingSpace::Description, EMBEDDING_MODEL).is_some());
+    assert!(metadata.embedding(EmbeddingSpace::Description, "mock-other").is_some());
+}
+
+#[test]
+fn tag_picks_the_label_from_the_description() {
+    let library = Library::new();
+    library.describe();
+    library.glimpse("tag", &["--tags", "red,green,blue"]);
+
+    assert_eq!(library.metadata("red.jpg").tags, vec!["red"]);
+    assert_eq!(library.metadata("red-again.jpg").tags, vec!["red"]);
+    assert_eq!(library.metadata("green.jpg").tags, vec!["green"]);
+    assert_eq!(library.metadata("blue.jpg").tags, vec!["blue"]);
+}
+
+#[test]
+fn find_ranks_matching_descriptions_first() {
+    let library = Library::new();
+    library.describe();
+
+    let found = results(&library.glimpse("find", &["--description", "mostly green"]));
+    assert_eq!(found.len(), FIXTURES.len());
+    assert_eq!(found[0], "green.jpg");
+}
+
+#[test]
+fn find_skips_files_embedded_with_other_models() {
+    let library = Library::new();
+    library.glimpse_on("tag-description", &library.file("green.jpg"), &["--embedding-model", "mock-other"]);
+    library.describe();
+
+    let stdout = library.glimpse("find", &["--query", "mostly green"]);
+    assert!(stdout.contains("Skipped 1 files with embeddings from other models"), "{}", stdout);
+    let found = results(&stdout);
+    assert_eq!(found.len(), FIXTURES.len() - 1);
+    assert!(!found.contains(&"green.jpg".to_string()));
+
+    let found = results(&library.glimpse("find", &["--query", "mostly green", "--all-models"]));
+    assert_eq!(found[0], "green.jpg");
+}
+
+#[test]
+fn find_similar_ranks_the_reference_and_its_lookalike_first() {
+    let library = Library::new();
+    library.describe();
+
+    let found = results(&library.glimpse("find-similar", &["--reference-file", &library.file("red.jpg")]));
+    assert_eq!(found.len(), FIXTURES.len());
+    let mut reds = found[..2].to_vec();
+    reds.sort();
+    assert_eq!(reds, vec!["red-again.jpg", "red.jpg"]);
+}
+
+#[test]
+fn tag_person_tags_matching_faces() {
+    let library = Library::new();
+    library.glimpse("tag-person", &["--person-name", "Alice", "--reference-file", &library.file("red.jpg")]);
+
+    assert_eq!(library.metadata("red.jpg").people, vec!["Alice"]);
+    assert_eq!(library.metadata("red-again.jpg").people, vec!["Alice"]);
+    assert!(library.metadata("green.jpg").people.is_empty());
+    assert!(library.metadata("blue.jpg").people.is_empty());
+    // Recorded as found by the mock, not as any real face backend
+    let faces = library.metadata("red.jpg").faces;
+    assert_eq!(faces.len(), 1);
+    assert_eq!(faces[0].source, FaceSource::Mock);
+
+    let mut found = results(&library.glimpse("find-person", &["--person-name", "Alice"]));
+    found.sort();
+    assert_eq!(found, vec!["red-again.jpg", "red.jpg"]);
+}
+
+#[test]
+fn tag_people_tags_enrolled_people() {
+    let library = Library::new();
+    library.glimpse_on("enroll-person", &library.file("red.jpg"), &["--person-name", "Alice"]);
+    library.glimpse_on("enroll-person", &library.file("green.jpg"), &["--person-name", "Bob"]);
+    library.glimpse("tag-people", &[]);
+
+    assert_eq!(library.metadata("red.jpg").people, vec!["Alice"]);
+    assert_eq!(library.metadata("red-again.jpg").people, vec!["Alice"]);
+    assert_eq!(library.metadata("green.jpg").people, vec!["Bob"]);
+    assert!(library.metadata("blue.jpg").people.is_empty());
+
+    let mut found = results(&library.glimpse("find-person", &["--person-name", "Alice"]));
+    found.sort();
+    assert_eq!(found, vec!["red-again.jpg", "red.jpg"]);
+}
+
+#[test]
+fn index_builds_a_catalog_searches_agree_with() {
+    let library = Library::new();
+    library.describe();
+    let query = ["--query", "mostly blue"];
+    let reference = ["--reference-file", &library.file("red.jpg")];
+    let found = results(&library.glimpse("find", &query));
+    let similar = results(&library.glimpse("find-similar", &reference));
+
+    library.glimpse("index", &[]);
+    assert!(library.directory.path().join(".glimpse.db").is_file());
+    assert_eq!(results(&library.glimpse("find", &query)), found);
+    assert_eq!(results(&library.glimpse("find-similar", &reference)), similar);
+
+    // Indexing again after a change picks it up
+    library.glimpse("tag", &["--tags", "red,green,blue"]);
+    library.glimpse("index", &[]);
+    assert_eq!(results(&library.glimpse("find", &["--query", "tag:blue"])), vec!["blue.jpg"]);
+}
+
+#[test]
+fn cluster_groups_and_names_albums() {
+    let library = Library::new();
+    library.describe();
+    library.glimpse("cluster", &["--clusters", "3", "--as-tag"]);
+
+    let red = library.metadata("red.jpg");
+    let red_again = library.metadata("red-again.jpg");
+    assert!(red.cluster_id.is_some());
+    assert_eq!(red.cluster_id, red_again.cluster_id);
+    assert_ne!(red.cluster_id, library.metadata("green.jpg").cluster_id);
+    assert_ne!(red.cluster_id, library.metadata("blue.jpg").cluster_id);
+    // Named by the mock chat model, and tagged with the name
+    assert!(!red.cluster_name.is_empty());
+    assert!(!red.cluster_name.starts_with("Cluster "));
+    assert!(red.tags.contains(&red.cluster_name));
+}
+
+#[test]
+fn cluster_without_names_numbers_albums() {
+    let library = Library::new();
+    library.describe();
+    library.glimpse("cluster", &["--clusters", "2", "--no-names"]);
+
+    for (name, _color, _pattern) in FIXTURES {
+        let metadata = library.metadata(name);
+        let cluster_id = metadata.cluster_id.unwrap();
+        assert_eq!(metadata.cluster_name, format!("Cluster {}", cluster_id));
+        assert!(metadata.tags.is_empty());
+    }
+}
+
+#[test]
+fn migrate_upgrades_legacy_metadata() {
+    let library = Library::new();
+    // Written before schema versions, with a single description embedding
+    let legacy = r#"{"people":["Alice"],"description":"A red image","description_embedding":[0.6,0.8],"description_embedding_model":"mock-embedding","tags":["red"]}"#;
+    let sidecar = format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:glimpse="https://github.com/feathj/glimpse/ns/1.0/">
+   <glimpse:PhotoMeta>{}</glimpse:PhotoMeta>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+        legacy.replace('"', "&quot;")
+    );
+    std::fs::write(xmp::sidecar_path(&library.file("red.jpg")), sidecar).unwrap();
+    let store = MetadataStore { storage: Storage::Xmp, ..MetadataStore::default() };
+    assert!(metadata::get_metadata(&store, &library.file("red.jpg")).unwrap().needs_migration());
+
+    let stdout = library.glimpse("migrate", &["--storage", "xmp"]);
+    assert!(stdout.contains("Migrated 1 files"), "{}", stdout);
+
+    let migrated = metadata::get_metadata(&store, &library.file("red.jpg")).unwrap();
+    assert!(!migrated.needs_migration());
+    assert_eq!(migrated.stored_schema_version, Some(metadata::SCHEMA_VERSION));
+    assert_eq!(migrated.people, vec!["Alice"]);
+    assert_eq!(migrated.tags, vec!["red"]);
+    let vector = migrated.embedding(EmbeddingSpace::Description, EMBEDDING_MODEL).unwrap();
+    assert!((vector[0] - 0.6).abs() < 1e-6 && (vector[1] - 0.8).abs() < 1e-6, "{:?}", vector);
+
+    // Nothing left to migrate
+    assert!(library.glimpse("migrate", &["--storage", "xmp"]).contains("Migrated 0 files"));
+}
+
+#[cfg(unix)]
+#[test]
+fn find_duplicates_only_links_exact_copies() {
+    use std::os::unix::fs::MetadataExt;
+
+    let library = Library::new();
+    // Equal pixels and size, so they rank equally and the first found is kept. The edited copy
+    // only differs in its JFIF pixel density, it shares the keeper's perceptual hash and length.
+    let keeper = library.file("dup-a-keeper.jpg");
+    let copy = library.file("dup-b-copy.jpg");
+    let edited = library.file("dup-c-edited.jpg");
+    std::fs::copy(library.file("red.jpg"), &keeper).unwrap();
+    std::fs::copy(library.file("red.jpg"), &copy).unwrap();
+    let mut bytes = std::fs::read(library.file("red.jpg")).unwrap();
+    bytes[15] ^= 1;
+    std::fs::write(&edited, &bytes).unwrap();
+
+    let stdout = library.glimpse_on("find-duplicates", &library.file("dup-*.jpg"), &["--hard-link"]);
+    assert!(stdout.contains(&format!("Linked {} to {}", copy, keeper)), "{}", stdout);
+    assert!(stdout.contains(&format!("Kept {}", edited)), "{}", stdout);
+
+    let inode = |file: &str| std::fs::metadata(file).unwrap().ino();
+    assert_eq!(inode(&copy), inode(&keeper));
+    assert_ne!(inode(&edited), inode(&keeper));
+    assert_eq!(std::fs::read(&edited).unwrap(), bytes);
+}
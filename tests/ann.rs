@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::error::Error;
+
+use glimpse::processing::ann::{self, Graph, Hnsw, Node};
+
+const DIMENSION: usize = 32;
+
+// Deterministic vectors, xorshift so the tests don't depend on a random crate
+fn random_vectors(count: usize, seed: u64) -> Vec<Vec<f64>> {
+    let mut state = seed;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+    };
+    (0..count).map(|_| (0..DIMENSION).map(|_| next()).collect()).collect()
+}
+
+fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    dot / (norm(a) * norm(b))
+}
+
+fn brute_force(vectors: &[Vec<f64>], query: &[f64], k: usize) -> Vec<usize> {
+    let mut similarities: Vec<(usize, f64)> = vectors
+        .iter()
+        .enumerate()
+        .map(|(node, vector)| (node, cosine_similarity(query, vector)))
+        .collect();
+    similarities.sort_by(|a, b| b.1.total_cmp(&a.1));
+    similarities.into_iter().take(k).map(|(node, _)| node).collect()
+}
+
+fn build(vectors: &[Vec<f64>]) -> Hnsw {
+    let mut index = Hnsw::new(DIMENSION);
+    for vector in vectors {
+        index.insert(vector).unwrap();
+    }
+    index
+}
+
+fn recall<G: Graph>(index: &G, vectors: &[Vec<f64>], queries: &[Vec<f64>], k: usize, ef: usize) -> f64 {
+    let mut found = 0;
+    for query in queries {
+        let expected: HashSet<usize> = brute_force(vectors, query, k).into_iter().collect();
+        found += ann::search(index, query, k, ef)
+            .unwrap()
+            .into_iter()
+            .filter(|(node, _distance)| expected.contains(node))
+            .count();
+    }
+    found as f64 / (queries.len() * k) as f64
+}
+
+// Nodes kept only in their stored form and decoded on every access, like the catalog's index
+struct Stored {
+    dimension: usize,
+    entry_point: Option<u32>,
+    nodes: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Stored {
+    fn new(index: &Hnsw) -> Stored {
+        Stored {
+            dimension: index.dimension(),
+            entry_point: index.entry_point(),
+            nodes: index
+                .nodes()
+                .iter()
+                .map(|node| (ann::vector_to_bytes(&node.vector), ann::links_to_bytes(&node.links)))
+                .collect(),
+        }
+    }
+}
+
+impl Graph for Stored {
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn entry_point(&self) -> Option<u32> {
+        self.entry_point
+    }
+
+    fn with_node<T>(&self, node: u32, f: impl FnOnce(&Node) -> T) -> Result<T, Box<dyn Error>> {
+        let (vector, links) = &self.nodes[node as usize];
+        Ok(f(&ann::node_from_bytes(vector, links, self.dimension, self.nodes.len())?))
+    }
+}
+
+#[test]
+fn search_recalls_brute_force_neighbours() {
+    let vectors = random_vectors(2000, 1);
+    let queries = random_vectors(50, 2);
+    let index = build(&vectors);
+
+    let recall = recall(&index, &vectors, &queries, 10, 64);
+    assert!(recall >= 0.9, "recall {} with ef 64", recall);
+}
+
+#[test]
+fn larger_ef_does_not_lower_recall() {
+    let vectors = random_vectors(2000, 3);
+    let queries = random_vectors(50, 4);
+    let index = build(&vectors);
+
+    let narrow = recall(&index, &vectors, &queries, 10, 10);
+    let wide = recall(&index, &vectors, &queries, 10, 200);
+    assert!(wide >= narrow, "recall {} with ef 200, {} with ef 10", wide, narrow);
+    assert!(wide >= 0.98, "recall {} with ef 200", wide);
+}
+
+#[test]
+fn small_index_finds_every_vector() {
+    let vectors = random_vectors(20, 5);
+    let index = build(&vectors);
+
+    for (node, vector) in vectors.iter().enumerate() {
+        let nearest = ann::search(&index, vector, 1, 20).unwrap();
+        assert_eq!(nearest[0].0, node);
+    }
+}
+
+#[test]
+fn stored_nodes_search_like_the_built_graph() {
+    let vectors = random_vectors(500, 6);
+    let queries = random_vectors(20, 7);
+    let index = build(&vectors);
+    let stored = Stored::new(&index);
+
+    for query in &queries {
+        assert_eq!(ann::search(&index, query, 10, 32).unwrap(), ann::search(&stored, query, 10, 32).unwrap());
+    }
+}
+
+#[test]
+fn query_of_another_dimension_finds_nothing() {
+    let index = build(&random_vectors(10, 8));
+    assert!(ann::search(&index, &[1.0, 0.0], 5, 5).unwrap().is_empty());
+}
+
+#[test]
+fn node_round_trips() {
+    let index = build(&random_vectors(100, 9));
+    for node in index.nodes() {
+        let vector = ann::vector_to_bytes(&node.vector);
+        let links = ann::links_to_bytes(&node.links);
+        assert_eq!(&ann::node_from_bytes(&vector, &links, DIMENSION, index.len()).unwrap(), node);
+    }
+}
+
+#[test]
+fn corrupt_nodes_are_errors() {
+    let vector = ann::vector_to_bytes(&[0.5; DIMENSION]);
+    let links = ann::links_to_bytes(&[vec![1, 2], vec![3]]);
+    assert!(ann::node_from_bytes(&vector, &links, DIMENSION, 4).is_ok());
+
+    // Link past the last node
+    assert!(ann::node_from_bytes(&vector, &links, DIMENSION, 3).is_err());
+    // Vector of another dimension
+    assert!(ann::node_from_bytes(&vector[..vector.len() - 4], &links, DIMENSION, 4).is_err());
+    // Truncated and trailing link bytes
+    assert!(ann::node_from_bytes(&vector, &links[..links.len() - 2], DIMENSION, 4).is_err());
+    let mut trailing = links.clone();
+    trailing.push(0);
+    assert!(ann::node_from_bytes(&vector, &trailing, DIMENSION, 4).is_err());
+    // Link counts far larger than the data
+    assert!(ann::node_from_bytes(&vector, &u32::MAX.to_le_bytes(), DIMENSION, 4).is_err());
+    // A node is always on layer 0
+    assert!(ann::node_from_bytes(&vector, &ann::links_to_bytes(&[]), DIMENSION, 4).is_err());
+}
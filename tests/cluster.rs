@@ -0,0 +1,51 @@
+use glimpse::processing::cluster;
+
+// Deterministic hashes in a few families of near-duplicates, xorshift so the tests don't depend on
+// a random crate
+fn hashes(count: usize) -> Vec<u64> {
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let families: Vec<u64> = (0..count / 10).map(|_| next()).collect();
+    (0..count)
+        .map(|i| {
+            let flips = next() % 6;
+            (0..flips).fold(families[i % families.len()], |hash, _| hash ^ (1 << (next() % 64)))
+        })
+        .collect()
+}
+
+fn brute_force(hashes: &[u64], threshold: u32) -> Vec<(usize, usize)> {
+    let mut links = vec![];
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if (hashes[i] ^ hashes[j]).count_ones() <= threshold {
+                links.push((i, j));
+            }
+        }
+    }
+    links
+}
+
+#[test]
+fn hamming_links_match_brute_force() {
+    let hashes = hashes(1000);
+    for threshold in [0, 3, 8, 20] {
+        assert_eq!(cluster::hamming_links(&hashes, threshold), brute_force(&hashes, threshold), "threshold {}", threshold);
+    }
+}
+
+#[test]
+fn identical_hashes_are_linked() {
+    assert_eq!(cluster::hamming_links(&[7, 7, 7], 0), vec![(0, 1), (0, 2), (1, 2)]);
+}
+
+#[test]
+fn linked_hashes_form_groups() {
+    let links = cluster::hamming_links(&[0b0000, 0b1111_0000_0000, 0b0001, 0b1111_0000_0001, 0b0011], 1);
+    assert_eq!(cluster::connected_groups(5, links), vec![vec![0, 2, 4], vec![1, 3]]);
+}